@@ -0,0 +1,173 @@
+use crate::compiler::{Block, Code, Instr, ScopeId};
+use crate::errors::Result;
+use crate::interpreter::{Binding, Op};
+use crate::pattern::Pattern;
+use crate::symbol::Symbol;
+use crate::value::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Code that can be called: the value of a block literal or a closure. Both have the type `Ops`.
+#[derive(Clone, Debug)]
+pub enum Quotation {
+    Block(Arc<Block>),
+    Closure(Arc<Closure>),
+}
+
+/// A quotation that was built at runtime. It behaves like a block of the ops it consists of.
+pub struct Closure {
+    kind: Kind,
+    code: Arc<Code>,
+    /// The values of the locals the code refers to.
+    locals: Vec<Locals>,
+}
+
+enum Kind {
+    /// A block that refers to locals of the code around it.
+    Capture(Arc<Block>),
+    /// Push the value, then call the quotation.
+    Curry(Value, Quotation),
+    /// Call the first quotation, then the second.
+    Compose(Quotation, Quotation),
+}
+
+/// The values of the locals declared by one `{ ... }`.
+#[derive(Clone, Debug)]
+pub(crate) struct Locals {
+    pub scope: ScopeId,
+    pub names: Arc<[Symbol]>,
+    pub values: Vec<Value>,
+}
+
+impl Closure {
+    /// A block compiled in the scope of locals, with their current values.
+    pub(crate) fn capture(block: Arc<Block>, code: Arc<Code>, locals: Vec<Locals>) -> Self {
+        Closure {
+            kind: Kind::Capture(block),
+            code,
+            locals,
+        }
+    }
+
+    /// A quotation that pushes `value` before calling `quotation`.
+    pub fn curry(value: Value, quotation: Quotation) -> Self {
+        let code = Code::synthetic(vec![
+            Instr::Push(value.clone()),
+            Instr::Apply(quotation.clone()),
+        ]);
+        Closure {
+            kind: Kind::Curry(value, quotation),
+            code: Arc::new(code),
+            locals: vec![],
+        }
+    }
+
+    /// A quotation that calls `first` and then `second`.
+    pub fn compose(first: Quotation, second: Quotation) -> Self {
+        let code = Code::synthetic(vec![
+            Instr::Apply(first.clone()),
+            Instr::Apply(second.clone()),
+        ]);
+        Closure {
+            kind: Kind::Compose(first, second),
+            code: Arc::new(code),
+            locals: vec![],
+        }
+    }
+
+    /// The ops the closure behaves like, with the captured locals replaced by their values.
+    pub fn ops(&self) -> Vec<Op> {
+        match &self.kind {
+            Kind::Capture(block) => {
+                let mut locals = self.locals.iter().collect::<Vec<_>>();
+                // inner scopes are declared later and shadow outer ones
+                locals.sort_by_key(|locals| locals.scope);
+                let values = locals
+                    .iter()
+                    .flat_map(|locals| locals.names.iter().copied().zip(locals.values.clone()))
+                    .collect();
+                substitute(block, values)
+            }
+            Kind::Curry(value, quotation) => {
+                let mut ops = vec![Op::Literal(value.clone())];
+                ops.extend(quotation.ops());
+                ops
+            }
+            Kind::Compose(first, second) => {
+                let mut ops = first.ops();
+                ops.extend(second.ops());
+                ops
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Closure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.ops().fmt(f)
+    }
+}
+
+/// Replace references to locals by their values, also in nested blocks.
+fn substitute(ops: &[Op], mut values: HashMap<Symbol, Value>) -> Vec<Op> {
+    let mut result = vec![];
+    for op in ops {
+        result.push(match op {
+            Op::Symbol(name, _) if values.contains_key(name) => Op::Literal(values[name].clone()),
+            Op::Literal(Value::Block(block)) => {
+                let ops = substitute(block, values.clone());
+                Op::Literal(Value::Block(Arc::new(Block::new(ops))))
+            }
+            Op::Locals(names, _) => {
+                for name in names.iter() {
+                    values.remove(name);
+                }
+                op.clone()
+            }
+            _ => op.clone(),
+        });
+    }
+    result
+}
+
+impl Quotation {
+    pub(crate) fn code(&self, env: &HashMap<Symbol, Binding>) -> Arc<Code> {
+        match self {
+            Quotation::Block(block) => block.code(env),
+            Quotation::Closure(closure) => closure.code.clone(),
+        }
+    }
+
+    /// The locals a frame that executes the quotation starts with.
+    pub(crate) fn locals(&self) -> &[Locals] {
+        match self {
+            Quotation::Block(_) => &[],
+            Quotation::Closure(closure) => &closure.locals,
+        }
+    }
+
+    /// The ops the quotation behaves like.
+    pub fn ops(&self) -> Vec<Op> {
+        match self {
+            Quotation::Block(block) => block.to_vec(),
+            Quotation::Closure(closure) => closure.ops(),
+        }
+    }
+
+    /// The quotation as a pattern of `match`. Blocks parse their pattern only once.
+    pub fn pattern(&self) -> Result<Arc<Pattern>> {
+        match self {
+            Quotation::Block(block) => block.pattern(),
+            Quotation::Closure(closure) => Pattern::parse(&closure.ops()).map(Arc::new),
+        }
+    }
+}
+
+impl From<Quotation> for Value {
+    fn from(quotation: Quotation) -> Self {
+        match quotation {
+            Quotation::Block(block) => Value::Block(block),
+            Quotation::Closure(closure) => Value::Closure(closure),
+        }
+    }
+}
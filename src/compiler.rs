@@ -0,0 +1,409 @@
+use crate::closure::Quotation;
+use crate::dispatch::Methods;
+use crate::errors::Result;
+use crate::interpreter::{Binding, Interpreter, Op};
+use crate::pattern::Pattern;
+use crate::source::Span;
+use crate::symbol::Symbol;
+use crate::value::Value;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// A sequence of ops, like a block literal or the body of a method, that is compiled the first
+/// time it is executed.
+pub struct Block {
+    ops: Arc<[Op]>,
+    code: OnceLock<Arc<Code>>,
+    /// The ops parsed as a pattern of `match`, the first time the block is used as one.
+    pattern: OnceLock<Result<Arc<Pattern>>>,
+}
+
+/// Compiled ops. Calls refer to the bindings of the called words, which stay the same when
+/// methods are added to them, so compiled code sees later definitions. Only words that were not
+/// defined at all when the code was compiled are looked up by name each time they are called.
+pub struct Code {
+    pub(crate) instrs: Vec<Instr>,
+    /// The op each instruction was compiled from, or `None` for instructions that were not
+    /// written by the user, like jumps inserted by the compiler.
+    pub(crate) ops: Vec<Option<Op>>,
+}
+
+pub(crate) enum Instr {
+    Push(Value),
+    Primitive(fn(&mut Interpreter) -> Result<()>),
+    Call(Arc<RwLock<Methods>>, Symbol, Span),
+    /// Call a word that was not defined when the code was compiled.
+    Lookup(Symbol, Span),
+    Tuple(usize),
+    Select(usize),
+    /// Pop values into the locals of a scope, the topmost value into the last local.
+    Bind(ScopeId, Arc<[Symbol]>),
+    /// Push the value of a local, which is looked up in the innermost frame that holds the scope.
+    Local(ScopeId, usize, Symbol),
+    /// Push a closure of the block over the values of the locals it refers to.
+    Capture(ScopedBlock),
+    /// Call a quotation.
+    Apply(Quotation),
+    /// Pop a boolean and continue at the given instruction if it is false.
+    JumpUnless(usize),
+    Jump(usize),
+    Try(ScopedBlock, ScopedBlock),
+    /// The ops of a definition, following `:`.
+    Define(Arc<[Op]>),
+    /// The ops of a type definition, following `:t`.
+    DefineType(Arc<[Op]>),
+    /// Code that cannot be compiled, which raises a parse error when it is reached.
+    Invalid(String),
+}
+
+/// A block compiled in the scope of locals, and the scopes of the locals it refers to.
+pub(crate) struct ScopedBlock {
+    pub block: Arc<Block>,
+    pub captures: Vec<ScopeId>,
+}
+
+/// Identifies the locals declared by one `{ ... }` in compiled code.
+pub(crate) type ScopeId = u64;
+
+static NEXT_SCOPE: AtomicU64 = AtomicU64::new(0);
+
+impl Block {
+    pub fn new(ops: impl Into<Arc<[Op]>>) -> Self {
+        Block {
+            ops: ops.into(),
+            code: OnceLock::new(),
+            pattern: OnceLock::new(),
+        }
+    }
+
+    fn compiled(ops: Arc<[Op]>, code: Code) -> Self {
+        Block {
+            ops,
+            code: OnceLock::from(Arc::new(code)),
+            pattern: OnceLock::new(),
+        }
+    }
+
+    /// The compiled code, resolving words in `env` if it is not compiled yet.
+    pub fn code(&self, env: &HashMap<Symbol, Binding>) -> Arc<Code> {
+        self.code
+            .get_or_init(|| Arc::new(compile(env, &self.ops)))
+            .clone()
+    }
+
+    /// The ops parsed as a pattern of `match`.
+    pub fn pattern(&self) -> Result<Arc<Pattern>> {
+        self.pattern
+            .get_or_init(|| Pattern::parse(&self.ops).map(Arc::new))
+            .clone()
+    }
+}
+
+impl Deref for Block {
+    type Target = [Op];
+
+    fn deref(&self) -> &[Op] {
+        &self.ops
+    }
+}
+
+impl PartialEq for Block {
+    fn eq(&self, other: &Self) -> bool {
+        self.ops == other.ops
+    }
+}
+
+impl std::fmt::Debug for Block {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.ops.fmt(f)
+    }
+}
+
+impl Code {
+    /// Code that consists of instructions the user did not write.
+    pub(crate) fn synthetic(instrs: Vec<Instr>) -> Self {
+        Code {
+            ops: instrs.iter().map(|_| None).collect(),
+            instrs,
+        }
+    }
+
+    pub(crate) fn op(&self, pc: usize) -> Option<&Op> {
+        self.ops.get(pc)?.as_ref()
+    }
+
+    pub(crate) fn span(&self, pc: usize) -> Option<Span> {
+        self.op(pc)?.span()
+    }
+
+    /// Whether execution ends without executing anything else when continuing at `pc`.
+    pub(crate) fn is_done(&self, mut pc: usize) -> bool {
+        loop {
+            match self.instrs.get(pc) {
+                None => return true,
+                Some(Instr::Jump(target)) => pc = *target,
+                Some(_) => return false,
+            }
+        }
+    }
+}
+
+/// Translate ops to instructions. The two blocks following `if` are compiled inline, so that
+/// `if` becomes a conditional jump. Calls of words defined in `env` are resolved to their bindings.
+pub fn compile(env: &HashMap<Symbol, Binding>, ops: &[Op]) -> Code {
+    Compiler::new(env, vec![]).finish(ops).0
+}
+
+struct Compiler<'a> {
+    env: &'a HashMap<Symbol, Binding>,
+    code: Code,
+    /// The locals of enclosing code, which blocks in this code may refer to.
+    outer: Vec<Scope>,
+    /// The locals declared so far in this code.
+    scopes: Vec<Scope>,
+    /// The scopes in `outer` this code refers to.
+    captures: Vec<ScopeId>,
+}
+
+#[derive(Clone)]
+struct Scope {
+    id: ScopeId,
+    names: Arc<[Symbol]>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(env: &'a HashMap<Symbol, Binding>, outer: Vec<Scope>) -> Self {
+        Compiler {
+            env,
+            code: Code::synthetic(vec![]),
+            outer,
+            scopes: vec![],
+            captures: vec![],
+        }
+    }
+
+    fn finish(mut self, ops: &[Op]) -> (Code, Vec<ScopeId>) {
+        self.compile(ops);
+        (self.code, self.captures)
+    }
+
+    /// Compile a block in the scope of the locals declared so far.
+    fn block(&mut self, block: &Arc<Block>) -> ScopedBlock {
+        if self.outer.is_empty() && self.scopes.is_empty() {
+            return ScopedBlock {
+                block: block.clone(),
+                captures: vec![],
+            };
+        }
+        let scopes = self.outer.iter().chain(&self.scopes).cloned().collect();
+        let (code, captures) = Compiler::new(self.env, scopes).finish(block);
+        for id in &captures {
+            self.capture(*id);
+        }
+        ScopedBlock {
+            block: Arc::new(Block::compiled(block.ops.clone(), code)),
+            captures,
+        }
+    }
+
+    /// Remember that the code refers to the scope `id`, if it is declared outside the code.
+    fn capture(&mut self, id: ScopeId) {
+        if self.outer.iter().any(|scope| scope.id == id) && !self.captures.contains(&id) {
+            self.captures.push(id);
+        }
+    }
+
+    /// The instruction that pushes the local `name`, if it is in scope.
+    fn local(&mut self, name: Symbol) -> Option<Instr> {
+        let (id, slot) = self
+            .outer
+            .iter()
+            .chain(&self.scopes)
+            .rev()
+            .find_map(|scope| {
+                let slot = scope.names.iter().rposition(|local| *local == name)?;
+                Some((scope.id, slot))
+            })?;
+        self.capture(id);
+        Some(Instr::Local(id, slot, name))
+    }
+
+    fn emit(&mut self, instr: Instr, op: Option<&Op>) -> usize {
+        self.code.instrs.push(instr);
+        self.code.ops.push(op.cloned());
+        self.code.instrs.len() - 1
+    }
+
+    /// Let the jump at `from` continue at the next instruction that is emitted.
+    fn patch(&mut self, from: usize) {
+        let here = self.code.instrs.len();
+        match &mut self.code.instrs[from] {
+            Instr::Jump(target) | Instr::JumpUnless(target) => *target = here,
+            _ => unreachable!(),
+        }
+    }
+
+    fn compile(&mut self, ops: &[Op]) {
+        let mut ops = ops.iter();
+        while let Some(op) = ops.next() {
+            let instr = match op {
+                Op::Literal(Value::Block(block)) => match self.block(block) {
+                    scoped if scoped.captures.is_empty() => Instr::Push(Value::Block(scoped.block)),
+                    scoped => Instr::Capture(scoped),
+                },
+                Op::Literal(value) => Instr::Push(value.clone()),
+                Op::Symbol(name, span) => match (self.local(*name), self.env.get(name)) {
+                    (Some(local), _) => local,
+                    (None, Some(Binding::Primitive(prim, _))) => Instr::Primitive(*prim),
+                    (None, Some(Binding::Composite(methods))) => {
+                        Instr::Call(methods.clone(), *name, span.clone())
+                    }
+                    (None, None) => Instr::Lookup(*name, span.clone()),
+                },
+                Op::Tuple(n) => Instr::Tuple(*n),
+                Op::Select(i, _) => Instr::Select(*i),
+                Op::Locals(names, _) => {
+                    let id = NEXT_SCOPE.fetch_add(1, Ordering::Relaxed);
+                    self.scopes.push(Scope {
+                        id,
+                        names: names.clone(),
+                    });
+                    Instr::Bind(id, names.clone())
+                }
+                Op::If(_) => {
+                    let (yes, no) = match branches(&mut ops) {
+                        Ok(branches) => branches,
+                        Err(message) => {
+                            self.emit(Instr::Invalid(message), Some(op));
+                            return;
+                        }
+                    };
+                    let skip_yes = self.emit(Instr::JumpUnless(0), Some(op));
+                    self.branch(&yes);
+                    let skip_no = self.emit(Instr::Jump(0), None);
+                    self.patch(skip_yes);
+                    self.branch(&no);
+                    self.patch(skip_no);
+                    continue;
+                }
+                Op::Try(_) => match branches(&mut ops) {
+                    Ok((body, handler)) => Instr::Try(self.block(&body), self.block(&handler)),
+                    Err(message) => {
+                        self.emit(Instr::Invalid(message), Some(op));
+                        return;
+                    }
+                },
+                Op::BeginDef(_) => Instr::Define(definition(&mut ops)),
+                Op::BeginTypeDef(_) => Instr::DefineType(definition(&mut ops)),
+                Op::End(_) | Op::Effect(_) => Instr::Invalid(format!("Unexpected {op}")),
+            };
+            self.emit(instr, Some(op));
+        }
+    }
+
+    /// Compile a branch of `if` inline. Locals it declares are not in scope after the branch.
+    fn branch(&mut self, ops: &[Op]) {
+        let scopes = self.scopes.len();
+        self.compile(ops);
+        self.scopes.truncate(scopes);
+    }
+}
+
+/// The two block literals following `if` or `try`.
+fn branches<'a>(
+    ops: &mut impl Iterator<Item = &'a Op>,
+) -> std::result::Result<(Arc<Block>, Arc<Block>), String> {
+    let mut branch = || match ops.next() {
+        None => Err("Unexpected end".to_string()),
+        Some(Op::Literal(Value::Block(block))) => Ok(block.clone()),
+        Some(_) => Err("Expected block literal".to_string()),
+    };
+    Ok((branch()?, branch()?))
+}
+
+/// The ops of a definition up to and including the `;` that ends it.
+fn definition<'a>(ops: &mut impl Iterator<Item = &'a Op>) -> Arc<[Op]> {
+    let mut definition = vec![];
+    for op in ops {
+        definition.push(op.clone());
+        if let Op::End(_) = op {
+            break;
+        }
+    }
+    definition.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn compile_src(src: &str) -> Code {
+        compile(&HashMap::new(), &parse("<test>", src).unwrap())
+    }
+
+    /// Whether the instruction pushes the local `name` rather than looking up a word.
+    fn is_local(instr: &Instr, name: &str) -> bool {
+        matches!(instr, Instr::Local(_, _, local) if *local == Symbol::new(name))
+    }
+
+    #[test]
+    fn if_jumps_over_the_branch_not_taken() {
+        let code = compile_src("c if [ 1 2 ] [ 3 ] 4");
+        // c, JumpUnless, 1, 2, Jump, 3, 4
+        assert!(matches!(code.instrs[1], Instr::JumpUnless(5)));
+        assert!(matches!(code.instrs[4], Instr::Jump(6)));
+        assert!(matches!(code.instrs[6], Instr::Push(Value::Int(4))));
+        // the jump out of the first branch was not written by the user
+        assert!(code.op(1).is_some());
+        assert!(code.op(4).is_none());
+    }
+
+    #[test]
+    fn if_without_blocks_is_invalid() {
+        let code = compile_src("c if [ 1 ]");
+        assert!(matches!(code.instrs.last(), Some(Instr::Invalid(_))));
+    }
+
+    #[test]
+    fn nested_if_in_tail_position_is_done() {
+        let code = compile_src("c if [ d if [ 1 ] [ 2 ] ] [ 3 ]");
+        let last_of_inner_yes = code
+            .instrs
+            .iter()
+            .position(|i| matches!(i, Instr::Push(Value::Int(1))))
+            .unwrap();
+        assert!(code.is_done(last_of_inner_yes + 1));
+    }
+
+    #[test]
+    fn locals_are_scoped_to_their_branch() {
+        let code = compile_src("c if [ { a } a ] [ a ] a");
+        // c, JumpUnless, Bind, a, Jump, a, a
+        assert!(matches!(code.instrs[2], Instr::Bind(..)));
+        assert!(is_local(&code.instrs[3], "a"));
+        assert!(matches!(code.instrs[5], Instr::Lookup(..)));
+        assert!(matches!(code.instrs[6], Instr::Lookup(..)));
+    }
+
+    #[test]
+    fn locals_declared_before_if_are_visible_in_both_branches() {
+        let code = compile_src("{ a } c if [ a ] [ a ] a");
+        // Bind, c, JumpUnless, a, Jump, a, a
+        assert!(is_local(&code.instrs[3], "a"));
+        assert!(is_local(&code.instrs[5], "a"));
+        assert!(is_local(&code.instrs[6], "a"));
+    }
+
+    #[test]
+    fn blocks_capture_the_locals_they_refer_to() {
+        let code = compile_src("{ a b } [ b ] [ 1 ]");
+        let Instr::Capture(scoped) = &code.instrs[1] else {
+            panic!("block with a local is not captured");
+        };
+        assert_eq!(scoped.captures.len(), 1);
+        assert!(matches!(code.instrs[2], Instr::Push(Value::Block(_))));
+    }
+}
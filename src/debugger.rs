@@ -0,0 +1,107 @@
+use crate::errors::{Backtrace, TraceEntry};
+use crate::interpreter::{Interpreter, Op, StackEffect};
+use crate::symbol::Symbol;
+use std::collections::HashSet;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+/// Called whenever execution stops at a breakpoint or after a step. It decides how to go on.
+pub type StopHook = Box<dyn FnMut(&mut Interpreter, &Stop) -> Command>;
+
+/// Breakpoints and stepping state of an interpreter.
+#[derive(Default)]
+pub struct Debugger {
+    /// Execution stops before any of these words is called.
+    pub breakpoints: HashSet<Symbol>,
+    pub(crate) mode: StepMode,
+    pub(crate) hook: Option<StopHook>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum StepMode {
+    /// Only stop at breakpoints.
+    #[default]
+    Run,
+    /// Stop before the next op.
+    Into,
+    /// Stop before the next op in the given frame, or after it returned.
+    Over { depth: usize, frame: u64 },
+    /// Stop once the frame stack is shallower than `depth`.
+    Out { depth: usize },
+}
+
+/// How to continue after a stop.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// Stop again before the next op, entering called words.
+    StepInto,
+    /// Stop before the next op of the current word, running calls to completion.
+    StepOver,
+    /// Run until the current word returns.
+    StepOut,
+    /// Run until the next breakpoint.
+    Continue,
+    /// Abort the evaluation with an `Interrupted` error.
+    Abort,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    Breakpoint(Symbol),
+    Step,
+}
+
+/// Where execution stopped.
+#[derive(Clone, Debug)]
+pub struct Stop {
+    pub reason: StopReason,
+    /// The op that is executed next.
+    pub op: Op,
+    /// The method that the op is going to call, if it calls a composite word with a method
+    /// matching the stack.
+    pub overload: Option<Arc<StackEffect>>,
+    /// The call stack, most recent call first.
+    pub trace: Vec<TraceEntry>,
+}
+
+impl Debugger {
+    /// Install the function that is called at every stop. Without a hook, breakpoints and
+    /// stepping have no effect.
+    pub fn set_hook(&mut self, hook: impl FnMut(&mut Interpreter, &Stop) -> Command + 'static) {
+        self.hook = Some(Box::new(hook));
+    }
+
+    /// Stop before the next op that is executed.
+    pub fn step(&mut self) {
+        self.mode = StepMode::Into;
+    }
+
+    /// Stop stepping and only stop at breakpoints.
+    pub fn resume(&mut self) {
+        self.mode = StepMode::Run;
+    }
+
+    /// Whether execution needs to be checked for stops at all.
+    pub(crate) fn is_active(&self) -> bool {
+        self.hook.is_some() && (self.mode != StepMode::Run || !self.breakpoints.is_empty())
+    }
+}
+
+impl std::fmt::Display for Stop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.reason {
+            StopReason::Breakpoint(word) => writeln!(f, "Breakpoint at {word}")?,
+            StopReason::Step => writeln!(f, "Step")?,
+        }
+        if let Some(span) = self.op.span() {
+            writeln!(f, "{span}")?;
+        }
+        write!(f, "Next op: {}", self.op)?;
+        if let Some(effect) = &self.overload {
+            write!(f, " {effect}")?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Call stack:")?;
+        write!(f, "{}", Backtrace::new(&self.trace))
+    }
+}
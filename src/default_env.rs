@@ -1,13 +1,20 @@
-use crate::errors::Result;
-use crate::interpreter::{Binding, Interpreter};
+use crate::closure::{Closure, Quotation};
+use crate::compiler::Instr;
+use crate::errors::{Error, ErrorKind, Result};
+use crate::interpreter::{Binding, Interpreter, StackEffect};
+use crate::serialize::QuotedStr;
 use crate::symbol::Symbol;
+use crate::value::Value;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 pub fn default_env() -> HashMap<Symbol, Binding> {
     let mut env = HashMap::new();
     let e = &mut env;
 
     housekeeping_primitives(e);
+    loop_primitives(e);
+    combinator_primitives(e);
     stackop_primitives(e);
     generic_primitives(e);
     integer_primitives(e);
@@ -20,29 +27,45 @@ pub fn default_env() -> HashMap<Symbol, Binding> {
 }
 
 fn housekeeping_primitives(e: &mut HashMap<Symbol, Binding>) {
-    primitive(e, "%error", |intp| {
+    primitive(e, "%error", "( Str -- * )", |intp| {
         let msg = intp.pop_str()?;
-        Err(msg.to_string())
+        Err(ErrorKind::User(Value::Str(msg)).into())
     });
 
-    primitive(e, "%apply", |intp| {
+    primitive(e, "%throw", "( x -- * )", |intp| {
+        let payload = intp.pop()?;
+        Err(ErrorKind::User(payload).into())
+    });
+
+    primitive(e, ":last-error", "( -- x )", |intp| {
+        let error = intp.last_error.as_ref().ok_or_else(|| {
+            Error::new(ErrorKind::User(Value::Str(Arc::new(
+                "No error occurred yet".to_string(),
+            ))))
+        })?;
+        intp.push(error.to_value());
+        Ok(())
+    });
+
+    primitive(e, "%apply", "( ... Ops -- ... )", |intp| {
         let ops = intp.pop_ops()?;
-        intp.exec(&ops)
+        intp.call(&ops);
+        Ok(())
     });
 
-    primitive(e, ":stacks", |intp| {
+    primitive(e, ":stacks", "( -- )", |intp| {
         println!("  Main Stack: {:?}", intp.main_stack);
         println!("Second Stack: {:?}", intp.secondary_stack);
         Ok(())
     });
 
-    primitive(e, ":words", |intp| {
+    primitive(e, ":words", "( -- )", |intp| {
         let mut names: Vec<_> = intp.env.keys().collect();
         names.sort();
         for name in names {
             print!("{:>40} ", name.to_string());
             match &intp.env[name] {
-                Binding::Primitive(_) => println!("|"),
+                Binding::Primitive(_, effect) => println!("| {:>30} |", effect.to_string()),
                 Binding::Composite(methods) => {
                     let methods = methods.read().unwrap();
                     let n = methods.len();
@@ -59,46 +82,310 @@ fn housekeeping_primitives(e: &mut HashMap<Symbol, Binding>) {
     });
 }
 
+fn loop_primitives(e: &mut HashMap<Symbol, Binding>) {
+    primitive(e, "%times", "( ... Ops Int -- ... )", times);
+    primitive(e, "%while", "( ... Ops Ops -- ... )", while_);
+    primitive(e, "%do", "( ... Ops Int Int -- ... )", do_);
+}
+
+// The loops run one iteration at a time on the frame stack and then schedule themselves again
+// with the remaining iterations, so that the body can recurse as deep as any other code.
+
+fn times(intp: &mut Interpreter) -> Result<()> {
+    intp.check_iteration()?;
+    let n = intp.pop_int()?;
+    let body = intp.pop_ops()?;
+    if n > 0 {
+        intp.call_instrs(vec![
+            Instr::Apply(body.clone()),
+            Instr::Push(body.into()),
+            Instr::Push(Value::Int(n - 1)),
+            Instr::Primitive(times),
+        ]);
+    }
+    Ok(())
+}
+
+fn while_(intp: &mut Interpreter) -> Result<()> {
+    intp.check_iteration()?;
+    let body = intp.pop_ops()?;
+    let cond = intp.pop_ops()?;
+    intp.call_instrs(vec![
+        Instr::Apply(cond.clone()),
+        Instr::JumpUnless(6),
+        Instr::Apply(body.clone()),
+        Instr::Push(cond.into()),
+        Instr::Push(body.into()),
+        Instr::Primitive(while_),
+    ]);
+    Ok(())
+}
+
+fn do_(intp: &mut Interpreter) -> Result<()> {
+    intp.check_iteration()?;
+    let limit = intp.pop_int()?;
+    let start = intp.pop_int()?;
+    let body = intp.pop_ops()?;
+    if start < limit {
+        intp.call_instrs(vec![
+            Instr::Push(Value::Int(start)),
+            Instr::Apply(body.clone()),
+            Instr::Push(body.into()),
+            Instr::Push(Value::Int(start + 1)),
+            Instr::Push(Value::Int(limit)),
+            Instr::Primitive(do_),
+        ]);
+    }
+    Ok(())
+}
+
+fn combinator_primitives(e: &mut HashMap<Symbol, Binding>) {
+    primitive(e, "%dip", "( ... x Ops -- ... x )", |intp| {
+        let ops = intp.pop_ops()?;
+        let x = intp.pop()?;
+        intp.call_instrs(vec![Instr::Apply(ops), Instr::Push(x)]);
+        Ok(())
+    });
+
+    primitive(e, "%2dip", "( ... x y Ops -- ... x y )", |intp| {
+        let ops = intp.pop_ops()?;
+        let y = intp.pop()?;
+        let x = intp.pop()?;
+        intp.call_instrs(vec![Instr::Apply(ops), Instr::Push(x), Instr::Push(y)]);
+        Ok(())
+    });
+
+    primitive(e, "%keep", "( ... x Ops -- ... x )", |intp| {
+        let ops = intp.pop_ops()?;
+        let x = intp.pop()?;
+        intp.call_instrs(vec![
+            Instr::Push(x.clone()),
+            Instr::Apply(ops),
+            Instr::Push(x),
+        ]);
+        Ok(())
+    });
+
+    primitive(e, "%bi", "( ... x Ops Ops -- ... )", |intp| {
+        let q = intp.pop_ops()?;
+        let p = intp.pop_ops()?;
+        let x = intp.pop()?;
+        intp.call_instrs(apply_each(x, vec![p, q]));
+        Ok(())
+    });
+
+    primitive(e, "%tri", "( ... x Ops Ops Ops -- ... )", |intp| {
+        let r = intp.pop_ops()?;
+        let q = intp.pop_ops()?;
+        let p = intp.pop_ops()?;
+        let x = intp.pop()?;
+        intp.call_instrs(apply_each(x, vec![p, q, r]));
+        Ok(())
+    });
+
+    primitive(e, "%cleave", "( ... x Ops -- ... )", |intp| {
+        let ops = intp.pop_ops()?;
+        let x = intp.pop()?;
+        with_quotations(intp, ops, vec![x], |intp| {
+            let x = intp.pop()?;
+            let quotations = pop_quotations(intp)?;
+            intp.call_instrs(apply_each(x, quotations));
+            Ok(())
+        });
+        Ok(())
+    });
+
+    primitive(e, "%bi@", "( ... x y Ops -- ... )", |intp| {
+        let ops = intp.pop_ops()?;
+        let y = intp.pop()?;
+        let x = intp.pop()?;
+        intp.call_instrs(vec![
+            Instr::Push(x),
+            Instr::Apply(ops.clone()),
+            Instr::Push(y),
+            Instr::Apply(ops),
+        ]);
+        Ok(())
+    });
+
+    primitive(e, "%curry", "( x Ops -- Ops )", |intp| {
+        let ops = intp.pop_ops()?;
+        let value = intp.pop()?;
+        intp.push(Value::Closure(Arc::new(Closure::curry(value, ops))));
+        Ok(())
+    });
+
+    primitive(e, "%compose", "( Ops Ops -- Ops )", |intp| {
+        let second = intp.pop_ops()?;
+        let first = intp.pop_ops()?;
+        intp.push(Value::Closure(Arc::new(Closure::compose(first, second))));
+        Ok(())
+    });
+
+    primitive(e, "%when", "( ... Bln Ops -- ... )", |intp| {
+        let ops = intp.pop_ops()?;
+        if intp.pop_bool()? {
+            intp.call(&ops);
+        }
+        Ok(())
+    });
+
+    primitive(e, "%unless", "( ... Bln Ops -- ... )", |intp| {
+        let ops = intp.pop_ops()?;
+        if !intp.pop_bool()? {
+            intp.call(&ops);
+        }
+        Ok(())
+    });
+
+    primitive(e, "%cond", "( ... Ops -- ... )", |intp| {
+        let ops = intp.pop_ops()?;
+        with_quotations(intp, ops, vec![], |intp| {
+            let cases = pop_quotations(intp)?;
+            let mut instrs = vec![];
+            let mut jumps_to_end = vec![];
+            let mut cases = cases.into_iter();
+            let mut default = None;
+            while let Some(test) = cases.next() {
+                let Some(body) = cases.next() else {
+                    // the last block without a body is the default case
+                    default = Some(test);
+                    break;
+                };
+                instrs.push(Instr::Apply(test));
+                instrs.push(Instr::JumpUnless(instrs.len() + 3));
+                instrs.push(Instr::Apply(body));
+                jumps_to_end.push(instrs.len());
+                instrs.push(Instr::Jump(0));
+            }
+            instrs.push(match default {
+                Some(ops) => Instr::Apply(ops),
+                None => Instr::Primitive(|_| {
+                    Err(ErrorKind::NoMatchingCase {
+                        word: Symbol::from_static("cond"),
+                        value: None,
+                    }
+                    .into())
+                }),
+            });
+            for i in jumps_to_end {
+                instrs[i] = Instr::Jump(instrs.len());
+            }
+            intp.call_instrs(instrs);
+            Ok(())
+        });
+        Ok(())
+    });
+
+    primitive(e, "%match", "( ... x Ops -- ... )", |intp| {
+        let ops = intp.pop_ops()?;
+        let value = intp.pop()?;
+        with_quotations(intp, ops, vec![value], |intp| {
+            let value = intp.pop()?;
+            let mut cases = pop_quotations(intp)?.into_iter();
+            while let Some(pattern) = cases.next() {
+                let Some(body) = cases.next() else {
+                    // the last block without a body is the default case
+                    intp.push(value);
+                    intp.call(&pattern);
+                    return Ok(());
+                };
+                if let Some(values) = pattern.pattern()?.bind(&value, &intp.families) {
+                    for value in values {
+                        intp.push(value);
+                    }
+                    intp.call(&body);
+                    return Ok(());
+                }
+            }
+            Err(ErrorKind::NoMatchingCase {
+                word: Symbol::from_static("match"),
+                value: Some(value),
+            }
+            .into())
+        });
+        Ok(())
+    });
+}
+
+/// Instructions that apply each quotation to its own copy of `x`.
+fn apply_each(x: Value, quotations: Vec<Quotation>) -> Vec<Instr> {
+    quotations
+        .into_iter()
+        .flat_map(|ops| [Instr::Push(x.clone()), Instr::Apply(ops)])
+        .collect()
+}
+
+/// Schedule a block that pushes quotations, like the blocks of `cond` or `cleave`, followed by
+/// `next`. `next` finds the values on top of the stack and can get the quotations below them from
+/// `pop_quotations`.
+fn with_quotations(
+    intp: &mut Interpreter,
+    ops: Quotation,
+    values: Vec<Value>,
+    next: fn(&mut Interpreter) -> Result<()>,
+) {
+    let depth = intp.main_stack.len() as i64;
+    let mut instrs = vec![Instr::Apply(ops), Instr::Push(Value::Int(depth))];
+    instrs.extend(values.into_iter().map(Instr::Push));
+    instrs.push(Instr::Primitive(next));
+    intp.call_instrs(instrs);
+}
+
+/// The quotations pushed by the block of `with_quotations`, once the values are popped.
+fn pop_quotations(intp: &mut Interpreter) -> Result<Vec<Quotation>> {
+    let depth = intp.pop_int()? as usize;
+    if intp.main_stack.len() < depth {
+        return Err(ErrorKind::StackUnderflow.into());
+    }
+    intp.main_stack
+        .split_off(depth)
+        .into_iter()
+        .map(Value::expect_block)
+        .collect()
+}
+
 fn stackop_primitives(e: &mut HashMap<Symbol, Binding>) {
-    primitive(e, "%>>", |intp| {
+    primitive(e, "%>>", "( x -- )", |intp| {
         let x = intp.pop()?;
         intp.secondary_stack.push(x);
         Ok(())
     });
 
-    primitive(e, "%<<", |intp| {
+    primitive(e, "%<<", "( -- x )", |intp| {
         let x = intp
             .secondary_stack
             .pop()
-            .ok_or_else(|| format!("pop from empty second stack"))?;
+            .ok_or(ErrorKind::StackUnderflow)?;
         intp.push(x);
         Ok(())
     });
 
-    primitive(e, "%@", |intp| {
+    primitive(e, "%@", "( Int -- x )", |intp| {
         let idx = intp.pop_int()? as usize;
         let x = intp
             .secondary_stack
-            .get(intp.secondary_stack.len() - 1 - idx)
-            .ok_or_else(|| format!("index out of bounds"))?
-            .clone();
+            .len()
+            .checked_sub(1 + idx)
+            .map(|i| intp.secondary_stack[i].clone())
+            .ok_or(ErrorKind::StackUnderflow)?;
         intp.push(x);
         Ok(())
     });
 
-    primitive(e, "%drop", |intp| {
+    primitive(e, "%drop", "( x -- )", |intp| {
         let _ = intp.pop()?;
         Ok(())
     });
 
-    primitive(e, "%dup", |intp| {
+    primitive(e, "%dup", "( x -- x x )", |intp| {
         let x = intp.pop()?;
         intp.push(x.clone());
         intp.push(x);
         Ok(())
     });
 
-    primitive(e, "%over", |intp| {
+    primitive(e, "%over", "( a b -- a b a )", |intp| {
         let b = intp.pop()?;
         let a = intp.pop()?;
         intp.push(a.clone());
@@ -107,7 +394,7 @@ fn stackop_primitives(e: &mut HashMap<Symbol, Binding>) {
         Ok(())
     });
 
-    primitive(e, "%swap", |intp| {
+    primitive(e, "%swap", "( a b -- b a )", |intp| {
         let b = intp.pop()?;
         let a = intp.pop()?;
         intp.push(b);
@@ -115,7 +402,7 @@ fn stackop_primitives(e: &mut HashMap<Symbol, Binding>) {
         Ok(())
     });
 
-    primitive(e, "%rot", |intp| {
+    primitive(e, "%rot", "( a b c -- b c a )", |intp| {
         let c = intp.pop()?;
         let b = intp.pop()?;
         let a = intp.pop()?;
@@ -127,13 +414,13 @@ fn stackop_primitives(e: &mut HashMap<Symbol, Binding>) {
 }
 
 fn generic_primitives(e: &mut HashMap<Symbol, Binding>) {
-    primitive(e, "%.", |intp| {
+    primitive(e, "%.", "( x -- )", |intp| {
         let x = intp.pop()?;
-        println!("{x:?}");
+        println!("{x}");
         Ok(())
     });
 
-    primitive(e, "%.=", |intp| {
+    primitive(e, "%.=", "( a b -- Bln )", |intp| {
         let b = intp.pop()?;
         let a = intp.pop()?;
         intp.push_bool(a == b);
@@ -142,26 +429,26 @@ fn generic_primitives(e: &mut HashMap<Symbol, Binding>) {
 }
 
 fn boolean_primitives(e: &mut HashMap<Symbol, Binding>) {
-    primitive(e, "%b.", |intp| {
+    primitive(e, "%b.", "( Bln -- )", |intp| {
         println!("{}", intp.pop_bool()?);
         Ok(())
     });
 
-    primitive(e, "%bb=", |intp| {
+    primitive(e, "%bb=", "( Bln Bln -- Bln )", |intp| {
         let b = intp.pop_bool()?;
         let a = intp.pop_bool()?;
         intp.push_bool(a == b);
         Ok(())
     });
 
-    primitive(e, "%bb&", |intp| {
+    primitive(e, "%bb&", "( Bln Bln -- Bln )", |intp| {
         let b = intp.pop_bool()?;
         let a = intp.pop_bool()?;
         intp.push_bool(a && b);
         Ok(())
     });
 
-    primitive(e, "%bb|", |intp| {
+    primitive(e, "%bb|", "( Bln Bln -- Bln )", |intp| {
         let b = intp.pop_bool()?;
         let a = intp.pop_bool()?;
         intp.push_bool(a || b);
@@ -170,47 +457,47 @@ fn boolean_primitives(e: &mut HashMap<Symbol, Binding>) {
 }
 
 fn integer_primitives(e: &mut HashMap<Symbol, Binding>) {
-    primitive(e, "%i.", |intp| {
+    primitive(e, "%i.", "( Int -- )", |intp| {
         println!("{}", intp.pop_int()?);
         Ok(())
     });
 
-    primitive(e, "%ii=", |intp| {
+    primitive(e, "%ii=", "( Int Int -- Bln )", |intp| {
         let b = intp.pop_int()?;
         let a = intp.pop_int()?;
         intp.push_bool(a == b);
         Ok(())
     });
 
-    primitive(e, "%ii<", |intp| {
+    primitive(e, "%ii<", "( Int Int -- Bln )", |intp| {
         let b = intp.pop_int()?;
         let a = intp.pop_int()?;
         intp.push_bool(a < b);
         Ok(())
     });
 
-    primitive(e, "%ii+", |intp| {
+    primitive(e, "%ii+", "( Int Int -- Int )", |intp| {
         let b = intp.pop_int()?;
         let a = intp.pop_int()?;
         intp.push_int(a + b);
         Ok(())
     });
 
-    primitive(e, "%ii-", |intp| {
+    primitive(e, "%ii-", "( Int Int -- Int )", |intp| {
         let b = intp.pop_int()?;
         let a = intp.pop_int()?;
         intp.push_int(a - b);
         Ok(())
     });
 
-    primitive(e, "%ii*", |intp| {
+    primitive(e, "%ii*", "( Int Int -- Int )", |intp| {
         let b = intp.pop_int()?;
         let a = intp.pop_int()?;
         intp.push_int(a * b);
         Ok(())
     });
 
-    primitive(e, "%ii/", |intp| {
+    primitive(e, "%ii/", "( Int Int -- Int )", |intp| {
         let b = intp.pop_int()?;
         let a = intp.pop_int()?;
         intp.push_int(a / b);
@@ -219,78 +506,78 @@ fn integer_primitives(e: &mut HashMap<Symbol, Binding>) {
 }
 
 fn float_primitives(e: &mut HashMap<Symbol, Binding>) {
-    primitive(e, "%f.", |intp| {
+    primitive(e, "%f.", "( Flt -- )", |intp| {
         println!("{}", intp.pop_flt()?);
         Ok(())
     });
 
-    primitive(e, "%i->f", |intp| {
+    primitive(e, "%i->f", "( Int -- Flt )", |intp| {
         let x = intp.pop_int()?;
         intp.push_flt(x as f64);
         Ok(())
     });
 
-    primitive(e, "%ff=", |intp| {
+    primitive(e, "%ff=", "( Flt Flt -- Bln )", |intp| {
         let b = intp.pop_flt()?;
         let a = intp.pop_flt()?;
         intp.push_bool(a == b);
         Ok(())
     });
 
-    primitive(e, "%ff<", |intp| {
+    primitive(e, "%ff<", "( Flt Flt -- Bln )", |intp| {
         let b = intp.pop_flt()?;
         let a = intp.pop_flt()?;
         intp.push_bool(a < b);
         Ok(())
     });
 
-    primitive(e, "%ff+", |intp| {
+    primitive(e, "%ff+", "( Flt Flt -- Flt )", |intp| {
         let b = intp.pop_flt()?;
         let a = intp.pop_flt()?;
         intp.push_flt(a + b);
         Ok(())
     });
 
-    primitive(e, "%ff-", |intp| {
+    primitive(e, "%ff-", "( Flt Flt -- Flt )", |intp| {
         let b = intp.pop_flt()?;
         let a = intp.pop_flt()?;
         intp.push_flt(a - b);
         Ok(())
     });
 
-    primitive(e, "%ff*", |intp| {
+    primitive(e, "%ff*", "( Flt Flt -- Flt )", |intp| {
         let b = intp.pop_flt()?;
         let a = intp.pop_flt()?;
         intp.push_flt(a * b);
         Ok(())
     });
 
-    primitive(e, "%ff/", |intp| {
+    primitive(e, "%ff/", "( Flt Flt -- Flt )", |intp| {
         let b = intp.pop_flt()?;
         let a = intp.pop_flt()?;
         intp.push_flt(a / b);
         Ok(())
     });
 
-    primitive(e, "%fsqrt", |intp| {
+    primitive(e, "%fsqrt", "( Flt -- Flt )", |intp| {
         let x = intp.pop_flt()?;
         intp.push_flt(x.sqrt());
         Ok(())
     });
 
-    primitive(e, "%flog", |intp| {
+    primitive(e, "%flog", "( Flt -- Flt )", |intp| {
         let x = intp.pop_flt()?;
         intp.push_flt(x.ln());
         Ok(())
     });
 
-    primitive(e, "%fsin", |intp| {
+    primitive(e, "%fsin", "( Flt -- Flt )", |intp| {
         let x = intp.pop_flt()?;
         intp.push_flt(x.sin());
         Ok(())
     });
 
-    primitive(e, "%fcos", |intp| {
+    primitive(e, "%fcos", "( Flt -- Flt )", |intp| {
         let x = intp.pop_flt()?;
         intp.push_flt(x.cos());
         Ok(())
@@ -298,23 +585,23 @@ fn float_primitives(e: &mut HashMap<Symbol, Binding>) {
 }
 
 fn string_primitives(e: &mut HashMap<Symbol, Binding>) {
-    primitive(e, "%s.", |intp| {
-        println!("{:?}", intp.pop_str()?);
+    primitive(e, "%s.", "( Str -- )", |intp| {
+        println!("{}", QuotedStr(&intp.pop_str()?));
         Ok(())
     });
-    primitive(e, "%println", |intp| {
+    primitive(e, "%println", "( Str -- )", |intp| {
         println!("{}", intp.pop_str()?);
         Ok(())
     });
 
-    primitive(e, "%ss=", |intp| {
+    primitive(e, "%ss=", "( Str Str -- Bln )", |intp| {
         let b = intp.pop_str()?;
         let a = intp.pop_str()?;
         intp.push_bool(a == b);
         Ok(())
     });
 
-    primitive(e, "%fmt", |intp| {
+    primitive(e, "%fmt", "( ... Str -- Str )", |intp| {
         let fmt_str = intp.pop_str()?;
         let mut fmt_str = fmt_str.chars();
         let mut str_out = String::new();
@@ -337,12 +624,12 @@ fn string_primitives(e: &mut HashMap<Symbol, Binding>) {
 }
 
 fn symbol_primitives(e: &mut HashMap<Symbol, Binding>) {
-    primitive(e, "%'.", |intp| {
+    primitive(e, "%'.", "( Sym -- )", |intp| {
         println!("{:?}", intp.pop_sym()?);
         Ok(())
     });
 
-    primitive(e, "%''=", |intp| {
+    primitive(e, "%''=", "( Sym Sym -- Bln )", |intp| {
         let b = intp.pop_sym()?;
         let a = intp.pop_sym()?;
         intp.push_bool(a == b);
@@ -350,10 +637,21 @@ fn symbol_primitives(e: &mut HashMap<Symbol, Binding>) {
     });
 }
 
+/// Define a primitive with its stack effect, written like `( a b -- b a )`.
 fn primitive(
     env: &mut HashMap<Symbol, Binding>,
     name: &'static str,
+    effect: &str,
     fun: fn(&mut Interpreter) -> Result<()>,
 ) {
-    env.insert(Symbol::from_static(name), Binding::Primitive(fun));
+    let effect = effect.trim_start_matches('(').trim_end_matches(')');
+    let (pre, post) = effect.split_once("--").expect("invalid stack effect");
+    let effect = StackEffect {
+        pre: pre.split_whitespace().map(Symbol::new).collect(),
+        post: post.split_whitespace().map(Symbol::new).collect(),
+    };
+    env.insert(
+        Symbol::from_static(name),
+        Binding::Primitive(fun, Arc::new(effect)),
+    );
 }
@@ -0,0 +1,543 @@
+use crate::interpreter::{Method, StackEffect};
+use crate::symbol::Symbol;
+use crate::value::Value;
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::ops::Deref;
+
+/// Maximum number of parameters of the methods of a word whose calls are cached.
+const CACHED_ARITY: usize = 4;
+
+/// How a method is selected when several methods of a word accept the stack.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DispatchPolicy {
+    /// The most recently defined method wins, so generic methods must be defined before the
+    /// specific ones.
+    #[default]
+    LastDefined,
+    /// The method with the most typed parameters wins, and among those the one with the fewest
+    /// families of sum types and then the one with the most parameters. Equally specific
+    /// methods with different signatures are ambiguous.
+    MostSpecific,
+}
+
+/// The outcome of selecting a method for a stack.
+pub enum Selection<'m> {
+    Found(&'m Method),
+    NoMatch,
+    /// Equally specific methods, most recently defined first.
+    Ambiguous(Vec<&'m Method>),
+}
+
+/// The methods of a composite word in the order of definition. Remembers which method was
+/// selected for the types on top of the stack, until a method is added.
+#[derive(Debug, Default)]
+pub struct Methods {
+    methods: Vec<Method>,
+    /// The largest number of parameters of a method. The types of this many values on top of the
+    /// stack determine which method is selected.
+    arity: usize,
+    cache: DispatchCache,
+}
+
+#[derive(Debug, Default)]
+struct DispatchCache {
+    policy: DispatchPolicy,
+    selected: HashMap<TypeKey, usize, BuildHasherDefault<KeyHasher>>,
+}
+
+/// The types of the values on top of the stack, topmost first, or `None` below the bottom.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TypeKey([Option<Symbol>; CACHED_ARITY]);
+
+impl Hash for TypeKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // symbols are interned, so their addresses identify them
+        for ty in &self.0 {
+            state.write_usize(ty.map_or(0, |ty| ty.0.as_ptr() as usize));
+        }
+    }
+}
+
+/// A fast hash for `TypeKey`, which only consists of addresses.
+#[derive(Default)]
+struct KeyHasher(u64);
+
+impl Hasher for KeyHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_u64(*byte as u64);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+}
+
+impl Methods {
+    pub fn push(&mut self, method: Method) {
+        self.arity = self.arity.max(method.effect.args().len());
+        self.methods.push(method);
+        self.cache.selected.clear();
+    }
+
+    fn key(&self, stack: &[Value]) -> Option<TypeKey> {
+        if self.arity > CACHED_ARITY {
+            return None;
+        }
+        let mut key = TypeKey([None; CACHED_ARITY]);
+        for (ty, value) in key.0.iter_mut().zip(stack.iter().rev().take(self.arity)) {
+            *ty = Some(value.get_type());
+        }
+        Some(key)
+    }
+
+    /// Forget which methods were selected, because the types a method accepts changed.
+    pub(crate) fn clear_cache(&mut self) {
+        self.cache.selected.clear();
+    }
+
+    /// The method that was selected the last time the stack had the same types on top.
+    pub(crate) fn cached(&self, policy: DispatchPolicy, stack: &[Value]) -> Option<&Method> {
+        if policy != self.cache.policy {
+            return None;
+        }
+        let index = self.cache.selected.get(&self.key(stack)?)?;
+        Some(&self.methods[*index])
+    }
+
+    /// The position of a method among the methods.
+    pub(crate) fn index_of(&self, method: &Method) -> Option<usize> {
+        self.methods.iter().position(|m| std::ptr::eq(m, method))
+    }
+
+    /// Remember that the method at `index` was selected for the stack, unless methods were
+    /// added since it was selected among the first `count` methods.
+    pub(crate) fn remember(
+        &mut self,
+        policy: DispatchPolicy,
+        stack: &[Value],
+        index: usize,
+        count: usize,
+    ) {
+        let Some(key) = self.key(stack) else {
+            return;
+        };
+        if count != self.methods.len() {
+            return;
+        }
+        if policy != self.cache.policy {
+            self.cache.policy = policy;
+            self.cache.selected.clear();
+        }
+        self.cache.selected.insert(key, index);
+    }
+}
+
+impl Deref for Methods {
+    type Target = [Method];
+
+    fn deref(&self) -> &[Method] {
+        &self.methods
+    }
+}
+
+impl From<Vec<Method>> for Methods {
+    fn from(methods: Vec<Method>) -> Self {
+        let mut result = Methods::default();
+        for method in methods {
+            result.push(method);
+        }
+        result
+    }
+}
+
+/// The sum types defined with `:t Family = Variant ... | Variant ... ;`. Values of a variant are
+/// accepted where the type of the family is expected.
+#[derive(Debug, Default)]
+pub struct Families {
+    variants: HashMap<Symbol, Vec<Symbol>>,
+    family: HashMap<Symbol, Symbol>,
+}
+
+impl Families {
+    /// Declare the variants of a family, replacing the variants it had before. A variant that
+    /// belonged to another family leaves it.
+    pub fn define(&mut self, family: Symbol, variants: Vec<Symbol>) {
+        for old in self.variants.remove(&family).unwrap_or_default() {
+            self.family.remove(&old);
+        }
+        for variant in &variants {
+            if let Some(other) = self.family.insert(*variant, family) {
+                if let Some(others) = self.variants.get_mut(&other) {
+                    others.retain(|v| v != variant);
+                }
+            }
+        }
+        self.variants.insert(family, variants);
+    }
+
+    /// The variants of a family, or `None` if `ty` is not a family.
+    pub fn variants(&self, ty: Symbol) -> Option<&[Symbol]> {
+        self.variants.get(&ty).map(Vec::as_slice)
+    }
+
+    /// The family a variant belongs to.
+    pub fn family(&self, variant: Symbol) -> Option<Symbol> {
+        self.family.get(&variant).copied()
+    }
+
+    /// Whether values of type `ty` are accepted where `expected` is expected.
+    pub fn is_a(&self, ty: Symbol, expected: Symbol) -> bool {
+        ty == expected || self.family(ty) == Some(expected)
+    }
+
+    /// `Shape` with the variants `Circle` and `Rect`, for tests.
+    #[cfg(test)]
+    pub(crate) fn shapes() -> Self {
+        let mut families = Families::default();
+        families.define(
+            Symbol::new("Shape"),
+            vec![Symbol::new("Circle"), Symbol::new("Rect")],
+        );
+        families
+    }
+}
+
+/// A method of an overloaded word, as listed by `resolution_order`.
+pub struct Overload<'m> {
+    pub method: &'m Method,
+    /// A method that is tried first and accepts every stack this one accepts, so that this one
+    /// is never selected.
+    pub shadowed_by: Option<&'m Method>,
+}
+
+/// Whether `a` accepts every stack that `b` accepts.
+pub fn covers(a: &StackEffect, b: &StackEffect, families: &Families) -> bool {
+    a.args().len() <= b.args().len()
+        && a.args()
+            .iter()
+            .rev()
+            .zip(b.args().iter().rev())
+            .all(|(x, y)| !x.is_type() || families.is_a(*y, *x))
+}
+
+/// Whether both effects accept exactly the same stacks.
+pub fn same_signature(a: &StackEffect, b: &StackEffect) -> bool {
+    a.pre.len() == b.pre.len()
+        && a.pre
+            .iter()
+            .zip(&b.pre)
+            .all(|(x, y)| x == y || !x.is_type() && !y.is_type())
+}
+
+impl DispatchPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "last-defined" => Some(DispatchPolicy::LastDefined),
+            "most-specific" => Some(DispatchPolicy::MostSpecific),
+            _ => None,
+        }
+    }
+
+    /// Select the method to call among the methods of a word that accept the stack.
+    pub fn select<'m>(
+        self,
+        methods: &'m [Method],
+        families: &Families,
+        accepts: impl Fn(&StackEffect) -> bool,
+    ) -> Selection<'m> {
+        let mut applicable = methods.iter().rev().filter(|m| accepts(&m.effect));
+        if self == DispatchPolicy::LastDefined {
+            return match applicable.next() {
+                Some(method) => Selection::Found(method),
+                None => Selection::NoMatch,
+            };
+        }
+
+        let mut best: Vec<&Method> = vec![];
+        for method in applicable {
+            let Some(first) = best.first() else {
+                best.push(method);
+                continue;
+            };
+            match specificity(&method.effect, families).cmp(&specificity(&first.effect, families)) {
+                Ordering::Greater => best = vec![method],
+                Ordering::Equal
+                    if !best
+                        .iter()
+                        .any(|m| same_signature(&m.effect, &method.effect)) =>
+                {
+                    best.push(method)
+                }
+                _ => {}
+            }
+        }
+        match best.len() {
+            0 => Selection::NoMatch,
+            1 => Selection::Found(best[0]),
+            _ => Selection::Ambiguous(best),
+        }
+    }
+}
+
+/// How specific an effect is for `DispatchPolicy::MostSpecific`: the number of typed parameters,
+/// then the number of those that are not families of sum types, then the number of parameters.
+pub fn specificity(effect: &StackEffect, families: &Families) -> (usize, usize, usize) {
+    let typed = effect.pre.iter().filter(|ty| ty.is_type()).count();
+    let families = effect
+        .pre
+        .iter()
+        .filter(|ty| families.variants(**ty).is_some())
+        .count();
+    (typed, typed - families, effect.pre.len())
+}
+
+/// The methods of a word in the order in which they are tried. Equally specific methods are
+/// tried most recently defined first.
+pub fn resolution_order<'m>(
+    methods: &'m [Method],
+    policy: DispatchPolicy,
+    families: &Families,
+) -> Vec<Overload<'m>> {
+    let mut methods: Vec<&Method> = methods.iter().rev().collect();
+    if policy == DispatchPolicy::MostSpecific {
+        methods.sort_by_key(|m| Reverse(specificity(&m.effect, families)));
+    }
+
+    let mut order: Vec<Overload> = vec![];
+    for method in methods {
+        let shadowed_by = order
+            .iter()
+            .map(|o| o.method)
+            .find(|m| covers(&m.effect, &method.effect, families));
+        order.push(Overload {
+            method,
+            shadowed_by,
+        });
+    }
+    order
+}
+
+/// The methods that could be selected so far, but not after a method with the effect `new` is
+/// added.
+pub fn newly_shadowed<'m>(
+    methods: &'m [Method],
+    new: &StackEffect,
+    policy: DispatchPolicy,
+    families: &Families,
+) -> Vec<&'m Method> {
+    // a method that accepts every stack another accepts is never more specific than it
+    let tried_first = |old: &StackEffect| match policy {
+        DispatchPolicy::LastDefined => true,
+        DispatchPolicy::MostSpecific => specificity(new, families) >= specificity(old, families),
+    };
+    resolution_order(methods, policy, families)
+        .into_iter()
+        .filter(|o| o.shadowed_by.is_none() && covers(new, &o.method.effect, families))
+        .filter(|o| tried_first(&o.method.effect))
+        .map(|o| o.method)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Block;
+    use std::sync::Arc;
+
+    fn effect(args: &str) -> StackEffect {
+        StackEffect {
+            pre: args.split_whitespace().map(Symbol::new).collect(),
+            post: vec![],
+        }
+    }
+
+    fn method(args: &str) -> Method {
+        Method {
+            effect: Arc::new(effect(args)),
+            doc: Default::default(),
+            body: Arc::new(Block::new(vec![])),
+        }
+    }
+
+    fn select<'m>(methods: &'m Methods, policy: DispatchPolicy, stack: &[Value]) -> &'m Method {
+        let accepts = |effect: &StackEffect| {
+            effect.args().len() <= stack.len()
+                && effect
+                    .args()
+                    .iter()
+                    .rev()
+                    .zip(stack.iter().rev())
+                    .all(|(ty, value)| !ty.is_type() || *ty == value.get_type())
+        };
+        match policy.select(methods, &Families::default(), accepts) {
+            Selection::Found(method) => method,
+            _ => panic!("no method selected"),
+        }
+    }
+
+    fn remember(methods: &mut Methods, policy: DispatchPolicy, stack: &[Value]) {
+        let index = methods.index_of(select(methods, policy, stack)).unwrap();
+        let count = methods.len();
+        methods.remember(policy, stack, index, count);
+    }
+
+    #[test]
+    fn remembers_the_method_selected_for_the_types_on_the_stack() {
+        let mut methods = Methods::from(vec![method("x"), method("Int"), method("Str")]);
+        let policy = DispatchPolicy::LastDefined;
+        remember(&mut methods, policy, &[Value::Int(1)]);
+
+        let cached = methods.cached(policy, &[Value::Int(2)]).unwrap();
+        assert!(std::ptr::eq(cached, &methods[1]));
+        assert!(methods.cached(policy, &[Value::Flt(2.0)]).is_none());
+        // only the types up to the largest arity are part of the key
+        let cached = methods.cached(policy, &[Value::Flt(1.0), Value::Int(2)]);
+        assert!(cached.is_some_and(|m| std::ptr::eq(m, &methods[1])));
+    }
+
+    #[test]
+    fn adding_a_method_forgets_the_selections() {
+        let mut methods = Methods::from(vec![method("x"), method("Int")]);
+        let policy = DispatchPolicy::LastDefined;
+        remember(&mut methods, policy, &[Value::Int(1)]);
+
+        methods.push(method("Int"));
+        assert!(methods.cached(policy, &[Value::Int(1)]).is_none());
+    }
+
+    #[test]
+    fn ignores_a_selection_made_before_a_method_was_added() {
+        let mut methods = Methods::from(vec![method("x"), method("Int")]);
+        let policy = DispatchPolicy::LastDefined;
+        let stack = [Value::Int(1)];
+        let count = methods.len();
+        methods.push(method("Int"));
+        methods.remember(policy, &stack, 1, count);
+        assert!(methods.cached(policy, &stack).is_none());
+    }
+
+    #[test]
+    fn selections_depend_on_the_policy() {
+        let mut methods = Methods::from(vec![method("Int x"), method("y z")]);
+        let stack = [Value::Int(1), Value::Int(2)];
+        remember(&mut methods, DispatchPolicy::LastDefined, &stack);
+
+        let most_specific = DispatchPolicy::MostSpecific;
+        assert!(methods.cached(most_specific, &stack).is_none());
+        remember(&mut methods, most_specific, &stack);
+        let cached = methods.cached(most_specific, &stack).unwrap();
+        assert!(std::ptr::eq(cached, &methods[0]));
+        assert!(methods
+            .cached(DispatchPolicy::LastDefined, &stack)
+            .is_none());
+    }
+
+    #[test]
+    fn does_not_cache_words_with_many_parameters() {
+        let mut methods = Methods::from(vec![method("a b c d e")]);
+        let policy = DispatchPolicy::LastDefined;
+        let stack = vec![Value::Int(0); 5];
+        remember(&mut methods, policy, &stack);
+        assert!(methods.cached(policy, &stack).is_none());
+    }
+
+    #[test]
+    fn distinguishes_stacks_shallower_than_the_arity() {
+        let mut methods = Methods::from(vec![method("x"), method("Int Int")]);
+        let policy = DispatchPolicy::LastDefined;
+        remember(&mut methods, policy, &[Value::Int(1)]);
+
+        assert!(methods
+            .cached(policy, &[Value::Int(1), Value::Int(2)])
+            .is_none());
+        let cached = methods.cached(policy, &[Value::Int(1)]).unwrap();
+        assert!(std::ptr::eq(cached, &methods[0]));
+    }
+
+    #[test]
+    fn covers_needs_fewer_or_wider_parameters() {
+        let families = Families::shapes();
+        let covers = |a, b| covers(&effect(a), &effect(b), &families);
+        assert!(covers("x", "Int"));
+        assert!(covers("Int", "Int"));
+        assert!(!covers("Int", "x"));
+        assert!(!covers("Int", "Flt"));
+        // only the parameters on top of the stack matter
+        assert!(covers("Int", "Str Int"));
+        assert!(!covers("Str Int", "Int"));
+        // a family covers its variants, but not the other way round
+        assert!(covers("Shape", "Circle"));
+        assert!(!covers("Circle", "Shape"));
+        assert!(!covers("Shape", "Int"));
+        // the values below a variable effect are not parameters
+        assert!(covers("... Ops", "Ops"));
+    }
+
+    #[test]
+    fn newly_shadowed_with_last_defined() {
+        let families = Families::shapes();
+        let methods = vec![method("Int"), method("Circle"), method("x Flt")];
+        let shadowed = |new| {
+            let policy = DispatchPolicy::LastDefined;
+            newly_shadowed(&methods, &effect(new), policy, &families)
+                .iter()
+                .map(|m| m.effect.pre.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(shadowed("Str"), Vec::<Vec<Symbol>>::new());
+        assert_eq!(shadowed("Shape"), [effect("Circle").pre]);
+        // a method with fewer parameters accepts every stack one with more parameters accepts
+        assert_eq!(shadowed("Flt"), [effect("x Flt").pre]);
+        assert_eq!(shadowed("x").len(), 3);
+    }
+
+    #[test]
+    fn methods_already_shadowed_are_not_reported_again() {
+        let families = Families::shapes();
+        let methods = vec![method("Int"), method("x")];
+        let policy = DispatchPolicy::LastDefined;
+        let shadowed = newly_shadowed(&methods, &effect("y"), policy, &families);
+        assert_eq!(shadowed.len(), 1);
+        assert!(std::ptr::eq(shadowed[0], &methods[1]));
+    }
+
+    #[test]
+    fn specificity_counts_typed_parameters_then_non_families_then_all() {
+        let families = Families::shapes();
+        let of = |args| specificity(&effect(args), &families);
+        assert_eq!(of("x y"), (0, 0, 2));
+        assert_eq!(of("Int y"), (1, 1, 2));
+        assert_eq!(of("Shape"), (1, 0, 1));
+        assert_eq!(of("Circle x"), (1, 1, 2));
+        assert!(of("Circle") > of("Shape"));
+        assert!(of("Shape Int") > of("Circle"));
+        assert!(of("x Int") > of("Int"));
+    }
+
+    #[test]
+    fn newly_shadowed_with_most_specific() {
+        let families = Families::shapes();
+        let methods = vec![method("Circle"), method("Int")];
+        let shadowed = |new| {
+            let policy = DispatchPolicy::MostSpecific;
+            newly_shadowed(&methods, &effect(new), policy, &families).len()
+        };
+        // a more general method is tried after the specific ones
+        assert_eq!(shadowed("x"), 0);
+        assert_eq!(shadowed("Shape"), 0);
+        // an equally specific method with the same parameters replaces the old one
+        assert_eq!(shadowed("Circle"), 1);
+    }
+}
@@ -0,0 +1,689 @@
+use crate::dispatch::{specificity, DispatchPolicy, Families};
+use crate::errors::{Error, Result};
+use crate::interpreter::{Binding, Method, Op, StackEffect};
+use crate::source::Span;
+use crate::symbol::Symbol;
+use crate::value::Value;
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// What to do with definitions whose body does not match their declared stack effect.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EffectCheck {
+    Off,
+    /// Print a warning and accept the definition anyway.
+    #[default]
+    Warn,
+    /// Reject the definition with an `InvalidDefinition` error.
+    Reject,
+}
+
+/// Verify that the body of a method is consistent with its declared stack effect.
+///
+/// The checker simulates the body on a stack of types. Values whose type is not known statically
+/// are represented by lower case names, like untyped stack effect entries. Calls are simulated
+/// with the declared effects of the called words, using the overloads that may be selected at
+/// runtime. Where the effect of the body cannot be determined, e.g. because a block is applied or
+/// overloads with different arities may be selected, the definition is accepted.
+pub fn check_method(
+    env: &HashMap<Symbol, Binding>,
+    families: &Families,
+    policy: DispatchPolicy,
+    name: Symbol,
+    method: &Method,
+) -> Result<()> {
+    let checker = Checker {
+        env,
+        families,
+        policy,
+        name,
+        effect: Some(&method.effect),
+        inference: None,
+        locals: Default::default(),
+    };
+    let result = match checker.simulate(&method.body, method.effect.pre.clone()) {
+        Err(Failure::Unknown(_)) | Ok(None) => return Ok(()),
+        Err(Failure::Mismatch(e)) => return Err(e),
+        Ok(Some(result)) => result,
+    };
+
+    let declared = &method.effect.post;
+    if method.effect.diverges() {
+        return Err(checker.error(format!(
+            "returns ( {} ) but is declared to never return",
+            Types(&result)
+        )));
+    }
+    let mismatch = result.len() != declared.len()
+        || result
+            .iter()
+            .zip(declared)
+            .any(|(found, expected)| conflicts(*found, *expected, families));
+    if mismatch {
+        return Err(checker.error(format!(
+            "returns ( {} ) but is declared to return ( {} )",
+            Types(&result),
+            Types(declared)
+        )));
+    }
+    Ok(())
+}
+
+/// Derive the stack effect of a body from the effects of the words it calls.
+///
+/// The body is simulated like in `check_method`, but on a stack of placeholders for the values
+/// it may take. The deepest placeholder the body touches determines the number of inputs. Inputs
+/// that are passed to words expecting a certain type get that type, and outputs that are inputs
+/// passed through keep their name, e.g. the body `%swap` has the effect `( a b -- b a )`.
+///
+/// `effect` is the declared effect of the method the body belongs to, if any. Without it, the
+/// body may not call `name`, because the effect of a recursive call is not known yet.
+pub fn infer_effect(
+    env: &HashMap<Symbol, Binding>,
+    families: &Families,
+    policy: DispatchPolicy,
+    name: Symbol,
+    body: &[Op],
+    effect: Option<&Arc<StackEffect>>,
+) -> Result<StackEffect> {
+    let inputs: Vec<Symbol> = (0..INPUT_NAMES.len())
+        .map(|i| Symbol::new(&format!("<input {i}>")))
+        .collect();
+    let checker = Checker {
+        env,
+        families,
+        policy,
+        name,
+        effect,
+        inference: Some(Inference {
+            inputs: inputs.clone(),
+            low_water: Cell::new(inputs.len()),
+            constraints: RefCell::new(HashMap::new()),
+        }),
+        locals: Default::default(),
+    };
+    let result = match checker.simulate(body, inputs.clone()) {
+        Err(Failure::Unknown(reason)) => {
+            return Err(Error::invalid_definition(format!(
+                "cannot infer the stack effect of {name}: {reason}"
+            )))
+        }
+        Err(Failure::Mismatch(e)) => return Err(e),
+        Ok(result) => result,
+    };
+
+    let low_water = checker.inference.as_ref().unwrap().low_water.get();
+    let names: HashMap<Symbol, Symbol> = inputs[low_water..]
+        .iter()
+        .zip(INPUT_NAMES)
+        .map(|(input, name)| (*input, Symbol::from_static(name)))
+        .collect();
+    let show = |ty: &Symbol| {
+        let ty = checker.resolve(*ty);
+        names.get(&ty).copied().unwrap_or(ty)
+    };
+    Ok(StackEffect {
+        pre: inputs[low_water..].iter().map(show).collect(),
+        post: match result {
+            None => vec![Symbol::from_static("*")],
+            Some(stack) => stack[low_water..].iter().map(show).collect(),
+        },
+    })
+}
+
+/// Names of inferred inputs. Inference gives up on bodies that take more values.
+const INPUT_NAMES: [&str; 16] = [
+    "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p",
+];
+
+/// The types on the stack, or `None` after code that never returns.
+type Stack = Option<Vec<Symbol>>;
+
+enum Failure {
+    /// The effect of the code cannot be determined statically, for the given reason.
+    Unknown(String),
+    Mismatch(Error),
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        Failure::Mismatch(e)
+    }
+}
+
+struct Checker<'a> {
+    env: &'a HashMap<Symbol, Binding>,
+    families: &'a Families,
+    policy: DispatchPolicy,
+    /// The word being defined. Its new method is not in `env` yet but may call itself.
+    name: Symbol,
+    /// The declared effect of the new method.
+    effect: Option<&'a Arc<StackEffect>>,
+    /// Set while inferring an effect instead of checking it.
+    inference: Option<Inference>,
+    /// The types of the values of the locals declared so far.
+    locals: RefCell<HashMap<Symbol, Symbol>>,
+}
+
+struct Inference {
+    /// Placeholders for the values the body may take, deepest first.
+    inputs: Vec<Symbol>,
+    /// The smallest stack depth reached. The inputs above it are taken by the body.
+    low_water: Cell<usize>,
+    /// Types that inputs must have, because they are passed to words expecting them.
+    constraints: RefCell<HashMap<Symbol, Symbol>>,
+}
+
+impl Checker<'_> {
+    fn error(&self, message: String) -> Error {
+        match self.effect {
+            Some(effect) => Error::invalid_definition(format!("{} {effect} {message}", self.name)),
+            None => Error::invalid_definition(format!("{} {message}", self.name)),
+        }
+    }
+
+    fn underflow(&self, span: &Span) -> Failure {
+        let message = "takes more values from the stack than declared".to_string();
+        Failure::Mismatch(self.error(message).at(span.clone()))
+    }
+
+    fn simulate(&self, ops: &[Op], mut stack: Vec<Symbol>) -> std::result::Result<Stack, Failure> {
+        let mut ops = ops.iter();
+        while let Some(op) = ops.next() {
+            match op {
+                Op::Literal(value) => stack.push(value.get_type()),
+                Op::Symbol(name, _) if self.locals.borrow().contains_key(name) => {
+                    stack.push(self.locals.borrow()[name])
+                }
+                Op::Symbol(name, span) => {
+                    if !self.call(*name, span, &mut stack)? {
+                        return Ok(None);
+                    }
+                }
+                Op::Select(i, span) => {
+                    let tuple = self.pop(&mut stack, span)?;
+                    if BUILTIN_TYPES.contains(&self.resolve(tuple).0) {
+                        self.expect(Symbol::from_static("Tuple"), tuple, span)?;
+                    }
+                    stack.push(tuple);
+                    stack.push(self.field_type(self.resolve(tuple), *i));
+                }
+                Op::Locals(names, span) => {
+                    let mut locals = self.locals.borrow_mut();
+                    for name in names.iter().rev() {
+                        locals.insert(*name, self.pop(&mut stack, span)?);
+                    }
+                }
+                Op::If(span) => {
+                    let cond = self.pop(&mut stack, span)?;
+                    self.expect(Symbol::from_static("Bln"), cond, span)?;
+                    let yes = self.simulate_branch(branch(ops.next(), span)?, stack.clone())?;
+                    let no = self.simulate_branch(branch(ops.next(), span)?, stack)?;
+                    match self.merge(yes, no, span)? {
+                        None => return Ok(None),
+                        Some(merged) => stack = merged,
+                    }
+                }
+                Op::Try(span) => {
+                    let body = self.simulate_branch(branch(ops.next(), span)?, stack.clone())?;
+                    stack.push(unknown());
+                    let handler = self.simulate_branch(branch(ops.next(), span)?, stack)?;
+                    match self.merge(body, handler, span)? {
+                        None => return Ok(None),
+                        Some(merged) => stack = merged,
+                    }
+                }
+                Op::Tuple(_)
+                | Op::BeginDef(_)
+                | Op::BeginTypeDef(_)
+                | Op::End(_)
+                | Op::Effect(_) => return Err(Failure::Unknown(format!("it contains {op}"))),
+            }
+        }
+        Ok(Some(stack))
+    }
+
+    /// Simulate a branch of `if` or `try`. Like in compiled code, the locals it declares are not
+    /// visible outside of it.
+    fn simulate_branch(
+        &self,
+        ops: &[Op],
+        stack: Vec<Symbol>,
+    ) -> std::result::Result<Stack, Failure> {
+        let locals = self.locals.borrow().clone();
+        let result = self.simulate(ops, stack);
+        *self.locals.borrow_mut() = locals;
+        result
+    }
+
+    /// Simulate a call of the word `name`. Returns false if the call never returns.
+    fn call(
+        &self,
+        name: Symbol,
+        span: &Span,
+        stack: &mut Vec<Symbol>,
+    ) -> std::result::Result<bool, Failure> {
+        let effect = match self.env.get(&name) {
+            Some(Binding::Primitive(_, effect)) => effect.clone(),
+            _ if name == self.name && self.effect.is_none() => {
+                return Err(Failure::Unknown(format!(
+                    "the recursive call of {name} needs a declared stack effect"
+                )))
+            }
+            Some(Binding::Composite(methods)) => {
+                let methods = methods.read().unwrap();
+                let mut effects: Vec<_> = methods.iter().map(|m| self.effective(m)).collect();
+                if let Some(effect) = self.effect.filter(|_| name == self.name) {
+                    effects.push(effect.clone());
+                }
+                let candidates = self.candidates(name, span, &effects, stack)?;
+                return self.call_overloaded(name, &candidates, span, stack);
+            }
+            None => match self.effect.filter(|_| name == self.name) {
+                Some(effect) => effect.clone(),
+                None => return Err(Failure::Unknown(format!("{name} is not defined"))),
+            },
+        };
+        if effect.is_variable() {
+            return Err(Failure::Unknown(format!(
+                "the effect of {name} depends on the values on the stack"
+            )));
+        }
+        self.apply(&effect, span, stack)
+    }
+
+    /// The declared effect of a method, or an effect that never returns if the body ends by
+    /// calling a word that never returns. Such methods are fallbacks that report an error.
+    fn effective(&self, method: &Method) -> Arc<StackEffect> {
+        let fails = match method.body.last() {
+            Some(Op::Symbol(name, _)) => match self.env.get(name) {
+                Some(Binding::Primitive(_, effect)) => effect.diverges(),
+                Some(Binding::Composite(methods)) => {
+                    let methods = methods.read().unwrap();
+                    !methods.is_empty() && methods.iter().all(|m| m.effect.diverges())
+                }
+                None => false,
+            },
+            _ => false,
+        };
+        if !fails || method.effect.is_variable() {
+            return method.effect.clone();
+        }
+        Arc::new(StackEffect {
+            pre: method.effect.pre.clone(),
+            post: vec![Symbol::from_static("*")],
+        })
+    }
+
+    /// The effects of the methods that may be selected for the stack, in the order in which they
+    /// are tried.
+    fn candidates<'m>(
+        &self,
+        name: Symbol,
+        span: &Span,
+        effects: &'m [Arc<StackEffect>],
+        stack: &[Symbol],
+    ) -> std::result::Result<Vec<&'m Arc<StackEffect>>, Failure> {
+        let mut order: Vec<_> = effects.iter().rev().collect();
+        if self.policy == DispatchPolicy::MostSpecific {
+            order.sort_by_key(|e| Reverse(specificity(e, self.families)));
+        }
+
+        let mut candidates = vec![];
+        let mut too_few_values = false;
+        // the specificity of a method that certainly accepts the stack
+        let mut selected = None;
+        for effect in order {
+            if selected.is_some_and(|s| specificity(effect, self.families) < s) {
+                break;
+            }
+            if effect.args().len() > stack.len() {
+                too_few_values = true;
+                continue;
+            }
+            let args = stack[stack.len() - effect.args().len()..]
+                .iter()
+                .map(|ty| self.resolve(*ty));
+            let mut certain = true;
+            let mut possible = true;
+            for (expected, found) in effect.args().iter().zip(args) {
+                if expected.is_type() {
+                    certain &= self.families.is_a(found, *expected);
+                    possible &= !conflicts(found, *expected, self.families);
+                }
+            }
+            if possible {
+                candidates.push(effect);
+                if certain && self.policy == DispatchPolicy::LastDefined {
+                    break;
+                }
+                if certain {
+                    selected = Some(specificity(effect, self.families));
+                }
+            }
+        }
+        if let [fallback] = &candidates[..] {
+            let overloaded = effects.iter().any(|e| e.pre.iter().any(Symbol::is_type));
+            if overloaded && !fallback.pre.iter().any(Symbol::is_type) {
+                return Err(Failure::Unknown(format!(
+                    "only the generic method of {name} matches, but a more specific one might \
+                     still be defined"
+                )));
+            }
+        }
+        if candidates.is_empty() && too_few_values {
+            return Err(self.underflow(span));
+        }
+        if candidates.is_empty() {
+            let arity = effects.iter().map(|e| e.pre.len()).max().unwrap_or(0);
+            let found: Vec<_> = stack[stack.len().saturating_sub(arity.max(1))..]
+                .iter()
+                .map(|ty| self.resolve(*ty))
+                .collect();
+            return Err(Error::invalid_definition(format!(
+                "no method of {name} accepts ( {} )",
+                Types(&found)
+            ))
+            .at(span.clone())
+            .into());
+        }
+        Ok(candidates)
+    }
+
+    fn call_overloaded(
+        &self,
+        name: Symbol,
+        candidates: &[&Arc<StackEffect>],
+        span: &Span,
+        stack: &mut Vec<Symbol>,
+    ) -> std::result::Result<bool, Failure> {
+        // methods that never return don't contribute to the stack after the call
+        let returning: Vec<_> = candidates.iter().filter(|e| !e.diverges()).collect();
+        if returning.iter().any(|e| e.is_variable()) {
+            return Err(Failure::Unknown(format!(
+                "the effect of {name} depends on the values on the stack"
+            )));
+        }
+        match &returning[..] {
+            [] => return self.apply(candidates[0], span, stack),
+            [effect] => return self.apply(effect, span, stack),
+            [.., fallback] if !fallback.pre.iter().any(Symbol::is_type) => {
+                return Err(Failure::Unknown(format!(
+                    "a generic method of {name} might be selected instead of a more specific one"
+                )));
+            }
+            _ => {}
+        }
+
+        // several methods may be selected; all of them need to have the same shape
+        let first = returning[0];
+        let same_shape = returning.iter().all(|e| {
+            e.pre.len() == first.pre.len() && e.post.len() == first.post.len() && !e.is_variable()
+        });
+        if !same_shape {
+            return Err(Failure::Unknown(format!(
+                "the methods of {name} that might be selected take or return different numbers \
+                 of values"
+            )));
+        }
+
+        // inputs and outputs have a type where all of them agree
+        let common = |i: usize, types: fn(&StackEffect) -> &[Symbol]| {
+            let ty = types(first)[i];
+            ty.is_type() && returning.iter().all(|e| types(e)[i] == ty)
+        };
+        let effect = StackEffect {
+            pre: (0..first.pre.len())
+                .map(|i| match common(i, |e| &e.pre) {
+                    true => first.pre[i],
+                    false => Symbol::from_static("_"),
+                })
+                .collect(),
+            post: (0..first.post.len())
+                .map(|i| match common(i, |e| &e.post) {
+                    true => first.post[i],
+                    false => unknown(),
+                })
+                .collect(),
+        };
+        self.apply(&effect, span, stack)
+    }
+
+    /// Replace the inputs of an effect on the stack by its outputs. Outputs with the same name
+    /// as an input get the type of that input. Returns false if the effect never returns.
+    fn apply(
+        &self,
+        effect: &StackEffect,
+        span: &Span,
+        stack: &mut Vec<Symbol>,
+    ) -> std::result::Result<bool, Failure> {
+        let mut bindings = HashMap::new();
+        for expected in effect.pre.iter().rev() {
+            let found = self.pop(stack, span)?;
+            self.expect(*expected, found, span)?;
+            if !expected.is_type() {
+                bindings.insert(*expected, found);
+            }
+        }
+        if effect.diverges() {
+            return Ok(false);
+        }
+        for ty in &effect.post {
+            stack.push(match bindings.get(ty) {
+                Some(bound) => *bound,
+                None if ty.is_type() => *ty,
+                None => unknown(),
+            });
+        }
+        Ok(true)
+    }
+
+    /// The type of field `i` of a tuple, if it is known from the type definition.
+    fn field_type(&self, tuple: Symbol, i: usize) -> Symbol {
+        if i == 0 {
+            return Symbol::from_static("Sym");
+        }
+        match self.env.get(&tuple) {
+            Some(Binding::Composite(methods)) => {
+                let methods = methods.read().unwrap();
+                methods
+                    .iter()
+                    .find(|m| m.effect.post == [tuple] && is_constructor(m, tuple))
+                    .and_then(|m| m.effect.pre.get(i - 1).copied())
+                    .unwrap_or_else(unknown)
+            }
+            _ => unknown(),
+        }
+    }
+
+    fn pop(&self, stack: &mut Vec<Symbol>, span: &Span) -> std::result::Result<Symbol, Failure> {
+        let Some(inference) = &self.inference else {
+            return stack.pop().ok_or_else(|| self.underflow(span));
+        };
+        let ty = stack.pop().ok_or_else(|| {
+            let max = INPUT_NAMES.len();
+            Failure::Unknown(format!("it takes more than {max} values"))
+        })?;
+        inference
+            .low_water
+            .set(inference.low_water.get().min(stack.len()));
+        Ok(ty)
+    }
+
+    fn expect(
+        &self,
+        expected: Symbol,
+        found: Symbol,
+        span: &Span,
+    ) -> std::result::Result<(), Failure> {
+        let found_type = self.resolve(found);
+        if conflicts(found_type, expected, self.families) {
+            return Err(Error::invalid_definition(format!(
+                "Found a {found_type} where {expected} was expected"
+            ))
+            .at(span.clone())
+            .into());
+        }
+        if let Some(inference) = &self.inference {
+            if expected.is_type() && inference.inputs.contains(&found) {
+                inference.constraints.borrow_mut().insert(found, expected);
+            }
+        }
+        Ok(())
+    }
+
+    /// The type of a value on the stack, taking into account what is known about inputs.
+    fn resolve(&self, ty: Symbol) -> Symbol {
+        self.inference
+            .as_ref()
+            .and_then(|inference| inference.constraints.borrow().get(&ty).copied())
+            .unwrap_or(ty)
+    }
+
+    /// Combine the stacks of two alternative code paths.
+    fn merge(&self, a: Stack, b: Stack, span: &Span) -> std::result::Result<Stack, Failure> {
+        let (a, b) = match (a, b) {
+            (None, other) | (other, None) => return Ok(other),
+            (Some(a), Some(b)) => (a, b),
+        };
+        if a.len() != b.len() {
+            if self.inference.is_some() {
+                let message = "branches leave different numbers of values on the stack";
+                return Err(Error::invalid_definition(message).at(span.clone()).into());
+            }
+            return Err(Error::invalid_definition(format!(
+                "branches leave different stacks ( {} ) and ( {} )",
+                Types(&a),
+                Types(&b)
+            ))
+            .at(span.clone())
+            .into());
+        }
+        Ok(Some(
+            a.iter()
+                .zip(&b)
+                .map(|(x, y)| match self.resolve(*x) == self.resolve(*y) {
+                    true if x == y => *x,
+                    true => self.resolve(*x),
+                    false => self
+                        .common_family(self.resolve(*x), self.resolve(*y))
+                        .unwrap_or_else(unknown),
+                })
+                .collect(),
+        ))
+    }
+
+    /// The family of sum types both types belong to, as variants or as the family itself.
+    fn common_family(&self, a: Symbol, b: Symbol) -> Option<Symbol> {
+        let family = |ty| match self.families.variants(ty) {
+            Some(_) => Some(ty),
+            None => self.families.family(ty),
+        };
+        family(a).filter(|f| family(b) == Some(*f))
+    }
+}
+
+fn is_constructor(method: &Method, ty: Symbol) -> bool {
+    matches!(&method.body[..], [Op::Literal(Value::Symbol(tag)), Op::Tuple(_)] if *tag == ty)
+}
+
+/// Types of values that are not tuples.
+const BUILTIN_TYPES: [&str; 6] = ["Bln", "Int", "Flt", "Str", "Sym", "Ops"];
+
+/// Both types are known, differ, and neither is a variant of the other.
+fn conflicts(a: Symbol, b: Symbol, families: &Families) -> bool {
+    a.is_type() && b.is_type() && !families.is_a(a, b) && !families.is_a(b, a)
+}
+
+fn unknown() -> Symbol {
+    Symbol::from_static("x")
+}
+
+fn branch<'o>(op: Option<&'o Op>, span: &Span) -> std::result::Result<&'o [Op], Failure> {
+    match op {
+        Some(Op::Literal(Value::Block(ops))) => Ok(ops),
+        _ => Err(Error::parse("Expected block literal")
+            .at(span.clone())
+            .into()),
+    }
+}
+
+struct Types<'a>(&'a [Symbol]);
+
+impl std::fmt::Display for Types<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<_> = self.0.iter().map(Symbol::to_string).collect();
+        write!(f, "{}", names.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::tests::{interpreter, run};
+    use crate::interpreter::Interpreter;
+    use crate::parser::parse;
+
+    /// An interpreter that rejects definitions which do not keep their declared effect.
+    fn checking() -> Interpreter {
+        let mut intp = interpreter();
+        intp.effect_check = EffectCheck::Reject;
+        run(
+            &mut intp,
+            ": apply ( ... Ops -- ... ) %apply ; \
+             : times ( ... Ops Int -- ... ) %times ; \
+             : y ( -- Str ) \"A word named like the locals\" \"y\" ;",
+        );
+        intp
+    }
+
+    fn define(intp: &mut Interpreter, src: &str) -> Result<()> {
+        intp.exec(parse("<test>", src)?)
+    }
+
+    #[test]
+    fn accepts_what_a_correct_definition_returns() {
+        let mut intp = checking();
+        assert!(define(&mut intp, ": f ( Int -- Int ) 1 %ii+ ;").is_ok());
+        assert!(define(&mut intp, ": g ( -- Int ) 1.0 ;").is_err());
+        assert!(define(&mut intp, ": h ( -- Int ) %ii+ ;").is_err());
+    }
+
+    #[test]
+    fn words_with_variable_effects_are_not_checked() {
+        let mut intp = checking();
+        assert!(define(&mut intp, ": three ( -- Int Int Int ) [ 1 ] 3 times ;").is_ok());
+        assert!(define(&mut intp, ": add ( Int Int -- Int ) [ %ii+ ] apply ;").is_ok());
+    }
+
+    #[test]
+    fn locals_of_one_branch_are_not_visible_in_the_other() {
+        let mut intp = checking();
+        run(
+            &mut intp,
+            ": z ( Int -- ) \"A word named like the locals\" %drop ;",
+        );
+        let src = ": f ( Int Int Bln -- Int ) if [ { z } ] [ z ] ;";
+        assert!(define(&mut intp, src).is_ok());
+    }
+
+    #[test]
+    fn locals_of_a_branch_are_not_visible_after_it() {
+        let mut intp = checking();
+        let src = ": f ( Int Bln -- Str ) if [ { y } ] [ %drop ] y ;";
+        assert!(define(&mut intp, src).is_ok());
+        let src = ": g ( Int -- Str ) try [ { y } \"a\" ] [ %drop %drop y ] ;";
+        assert!(define(&mut intp, src).is_ok());
+    }
+
+    #[test]
+    fn locals_declared_before_a_branch_are_visible_in_it() {
+        let mut intp = checking();
+        let src = ": f ( Int Bln -- Int ) { x b } b if [ x ] [ x 1 %ii+ ] ;";
+        assert!(define(&mut intp, src).is_ok());
+        let src = ": g ( Int Bln -- Str ) { x b } b if [ x ] [ x ] ;";
+        assert!(define(&mut intp, src).is_err());
+    }
+}
@@ -1,2 +1,320 @@
+use crate::interpreter::StackEffect;
+use crate::source::Span;
+use crate::symbol::Symbol;
+use crate::value::Value;
+use std::fmt::Formatter;
+use std::sync::Arc;
 
-pub type Result<T> = std::result::Result<T, String>;
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub span: Option<Span>,
+    /// The call stack at the point where the error occurred, most recent call first.
+    pub trace: Vec<TraceEntry>,
+}
+
+/// A resource limit of the interpreter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    CallDepth,
+    Nesting,
+    MainStack,
+    SecondaryStack,
+    Fuel,
+}
+
+/// One call on the call stack.
+#[derive(Clone, Debug)]
+pub struct TraceEntry {
+    /// The called word, or `None` at top level.
+    pub word: Option<Symbol>,
+    /// The signature of the method that was selected for the call.
+    pub effect: Option<Arc<StackEffect>>,
+    /// Whether a block inside the word is executed, rather than its body.
+    pub is_block: bool,
+    /// Index of the executed instruction within the compiled body or block.
+    pub op_index: usize,
+    pub span: Option<Span>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// Popped from the main stack or the secondary stack when it did not hold enough values.
+    StackUnderflow,
+    TypeMismatch {
+        expected: Symbol,
+        found: Symbol,
+    },
+    UnknownWord(Symbol),
+    /// A `#n` select operator was applied to a tuple with fewer fields.
+    NoSuchField {
+        field: usize,
+        found: Symbol,
+    },
+    /// None of the methods of `word` accepts the types on top of the stack.
+    NoMatchingMethod {
+        word: Symbol,
+        found: Vec<Symbol>,
+    },
+    /// With the most specific dispatch policy, several methods of `word` with different
+    /// signatures accept the stack and none of them is more specific than the others.
+    AmbiguousMethod {
+        word: Symbol,
+        found: Vec<Symbol>,
+        candidates: Vec<Arc<StackEffect>>,
+    },
+    /// None of the cases given to `word`, like `cond`, applies. `value` is the value `match` was
+    /// given.
+    NoMatchingCase {
+        word: Symbol,
+        value: Option<Value>,
+    },
+    LimitExceeded(Limit),
+    /// In strict mode, a method returned a stack that does not match its declared effect.
+    /// `found` holds the types of the returned values, or `None` if the method consumed more
+    /// values than declared.
+    ContractViolation {
+        word: Symbol,
+        effect: Arc<StackEffect>,
+        found: Option<Vec<Symbol>>,
+    },
+    /// The evaluation was aborted from outside. `try` does not catch this error.
+    Interrupted,
+    /// Raised by the program itself, carrying an arbitrary value.
+    User(Value),
+    Parse(String),
+    InvalidDefinition(String),
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Error {
+            kind,
+            span: None,
+            trace: vec![],
+        }
+    }
+
+    pub fn parse(message: impl Into<String>) -> Self {
+        Error::new(ErrorKind::Parse(message.into()))
+    }
+
+    pub fn invalid_definition(message: impl Into<String>) -> Self {
+        Error::new(ErrorKind::InvalidDefinition(message.into()))
+    }
+
+    /// Attach a source location, unless the error already knows a more precise one.
+    pub fn at(mut self, span: Span) -> Self {
+        if self.span.is_none() {
+            self.span = Some(span);
+        }
+        self
+    }
+
+    /// Displays the call stack of the error, one call per line.
+    pub fn backtrace(&self) -> Backtrace<'_> {
+        Backtrace(&self.trace)
+    }
+
+    /// The error as seen by Forthly code. User errors are represented by their payload. All
+    /// other errors are tuples named after their kind, whose first field is the message.
+    pub fn to_value(&self) -> Value {
+        let mut fields = vec![
+            Value::Symbol(Symbol::from_static(self.kind.name())),
+            Value::Str(Arc::new(self.kind.to_string())),
+        ];
+        match &self.kind {
+            ErrorKind::User(payload) => return payload.clone(),
+            ErrorKind::TypeMismatch { expected, found } => {
+                fields.push(Value::Symbol(*expected));
+                fields.push(Value::Symbol(*found));
+            }
+            ErrorKind::UnknownWord(word)
+            | ErrorKind::NoMatchingMethod { word, .. }
+            | ErrorKind::AmbiguousMethod { word, .. }
+            | ErrorKind::NoMatchingCase { word, .. }
+            | ErrorKind::ContractViolation { word, .. } => fields.push(Value::Symbol(*word)),
+            ErrorKind::NoSuchField { found, .. } => fields.push(Value::Symbol(*found)),
+            ErrorKind::LimitExceeded(limit) => {
+                fields.push(Value::Symbol(Symbol::from_static(limit.name())))
+            }
+            ErrorKind::StackUnderflow
+            | ErrorKind::Interrupted
+            | ErrorKind::Parse(_)
+            | ErrorKind::InvalidDefinition(_) => {}
+        }
+        Value::Tuple(Arc::new(fields))
+    }
+}
+
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::StackUnderflow => "StackUnderflow",
+            ErrorKind::TypeMismatch { .. } => "TypeMismatch",
+            ErrorKind::UnknownWord(_) => "UnknownWord",
+            ErrorKind::NoSuchField { .. } => "NoSuchField",
+            ErrorKind::NoMatchingMethod { .. } => "NoMatchingMethod",
+            ErrorKind::AmbiguousMethod { .. } => "AmbiguousMethod",
+            ErrorKind::NoMatchingCase { .. } => "NoMatchingCase",
+            ErrorKind::LimitExceeded(_) => "LimitExceeded",
+            ErrorKind::ContractViolation { .. } => "ContractViolation",
+            ErrorKind::Interrupted => "Interrupted",
+            ErrorKind::User(_) => "UserError",
+            ErrorKind::Parse(_) => "ParseError",
+            ErrorKind::InvalidDefinition(_) => "InvalidDefinition",
+        }
+    }
+}
+
+impl Limit {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Limit::CallDepth => "call-depth",
+            Limit::Nesting => "nesting",
+            Limit::MainStack => "main-stack",
+            Limit::SecondaryStack => "secondary-stack",
+            Limit::Fuel => "fuel",
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error::new(kind)
+    }
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::StackUnderflow => write!(f, "Pop from empty stack"),
+            ErrorKind::TypeMismatch { expected, found } => {
+                write!(f, "Found a {found} where {expected} was expected")
+            }
+            ErrorKind::UnknownWord(name) => write!(f, "Unknown {name}"),
+            ErrorKind::NoSuchField { field, found } => write!(f, "{found} has no field #{field}"),
+            ErrorKind::NoMatchingMethod { word, found } => {
+                write!(f, "found no matching method for {word} with stack (")?;
+                for ty in found {
+                    write!(f, " {ty}")?;
+                }
+                write!(f, " )")
+            }
+            ErrorKind::AmbiguousMethod {
+                word,
+                found,
+                candidates,
+            } => {
+                write!(f, "ambiguous call of {word} with stack (")?;
+                for ty in found {
+                    write!(f, " {ty}")?;
+                }
+                write!(f, " ): methods")?;
+                for (i, effect) in candidates.iter().enumerate() {
+                    match i {
+                        0 => write!(f, " {effect}")?,
+                        _ if i + 1 == candidates.len() => write!(f, " and {effect}")?,
+                        _ => write!(f, ", {effect}")?,
+                    }
+                }
+                write!(f, " are equally specific")
+            }
+            ErrorKind::NoMatchingCase { word, value: None } => {
+                write!(f, "none of the cases of {word} applies")
+            }
+            ErrorKind::NoMatchingCase {
+                word,
+                value: Some(value),
+            } => write!(
+                f,
+                "none of the cases of {word} applies to {value} ( {} )",
+                value.get_type()
+            ),
+            ErrorKind::LimitExceeded(Limit::Fuel) => write!(f, "Out of fuel"),
+            ErrorKind::LimitExceeded(limit) => write!(f, "Exceeded the {} limit", limit.name()),
+            ErrorKind::ContractViolation {
+                word,
+                effect,
+                found: None,
+            } => write!(f, "{word} {effect} took more values than declared"),
+            ErrorKind::ContractViolation {
+                word,
+                effect,
+                found: Some(found),
+            } => {
+                write!(f, "{word} {effect} returned (")?;
+                for ty in found {
+                    write!(f, " {ty}")?;
+                }
+                write!(f, " )")
+            }
+            ErrorKind::Interrupted => write!(f, "Interrupted"),
+            ErrorKind::User(Value::Str(message)) => write!(f, "{message}"),
+            ErrorKind::User(payload) => write!(f, "{payload}"),
+            ErrorKind::Parse(message) | ErrorKind::InvalidDefinition(message) => {
+                write!(f, "{message}")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(span) = &self.span {
+            write!(f, "\n{span}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_block {
+            write!(f, "block in ")?;
+        }
+        match (&self.word, &self.effect) {
+            (Some(word), Some(effect)) => write!(f, "{word} {effect}")?,
+            (Some(word), None) => write!(f, "{word}")?,
+            (None, _) => write!(f, "<top level>")?,
+        }
+        write!(f, ", op {}", self.op_index)?;
+        if let Some(span) = &self.span {
+            write!(f, " at {}", span.location())?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Backtrace<'a>(&'a [TraceEntry]);
+
+impl<'a> Backtrace<'a> {
+    pub fn new(trace: &'a [TraceEntry]) -> Self {
+        Backtrace(trace)
+    }
+}
+
+impl std::fmt::Display for Backtrace<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        const HEAD: usize = 15;
+        const TAIL: usize = 5;
+        for (i, entry) in self.0.iter().enumerate() {
+            if self.0.len() > HEAD + TAIL && i == HEAD {
+                writeln!(
+                    f,
+                    "      ... {} calls omitted ...",
+                    self.0.len() - HEAD - TAIL
+                )?;
+            }
+            if self.0.len() <= HEAD + TAIL || i < HEAD || i >= self.0.len() - TAIL {
+                writeln!(f, "{i:>4}: {entry}")?;
+            }
+        }
+        Ok(())
+    }
+}
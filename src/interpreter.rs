@@ -1,11 +1,23 @@
+use crate::closure::{Closure, Locals, Quotation};
+use crate::compiler::{Block, Code, Instr, ScopeId, ScopedBlock};
+use crate::debugger::{Command, Debugger, StepMode, Stop, StopReason};
 use crate::default_env::default_env;
-use crate::errors::Result;
+use crate::dispatch::{
+    newly_shadowed, same_signature, DispatchPolicy, Families, Methods, Selection,
+};
+use crate::effects::{check_method, infer_effect, EffectCheck};
+use crate::errors::{Error, ErrorKind, Limit, Result, TraceEntry};
 use crate::parser::parse;
-use crate::serialize::DisplayBlock;
+use crate::pattern::{check_patterns, uncovered_variants};
+use crate::profiler::Profiler;
+use crate::serialize::{DisplayBlock, QuotedStr};
+use crate::source::Span;
 use crate::symbol::Symbol;
+use crate::tracer::Tracer;
 use crate::value::Value;
 use std::collections::HashMap;
 use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 
 pub trait ExecutionContext {
@@ -16,51 +28,230 @@ pub struct Interpreter {
     enable_log: bool,
     pub main_stack: Vec<Value>,
     pub secondary_stack: Vec<Value>,
+    /// The defined words. Compiled code refers to the bindings of the words it calls, so a
+    /// binding must be updated in place rather than replaced when a word is redefined.
     pub env: HashMap<Symbol, Binding>,
+    /// The sum types. Their variants are accepted where the family is expected.
+    pub families: Families,
+    frames: Vec<Frame>,
+    /// Number of run loops that are active on the Rust stack.
+    nesting: usize,
+    pub limits: Limits,
+    /// How the method of an overloaded word is selected.
+    pub dispatch: DispatchPolicy,
+    /// Whether definitions are checked against their declared stack effects.
+    pub effect_check: EffectCheck,
+    /// Verify at runtime that every method call keeps its declared stack effect.
+    pub strict: bool,
+    /// Set from outside, e.g. by a signal handler, to abort the running evaluation.
+    interrupt: Arc<AtomicBool>,
+    /// The most recent error reported to the user, available to programs through `:last-error`.
+    pub last_error: Option<Error>,
+    pub debugger: Debugger,
+    /// Logs executed ops while it is set.
+    pub tracer: Option<Tracer>,
+    /// Collects statistics about executed words while it is set.
+    pub profiler: Option<Profiler>,
+    /// Identifies the next frame that is pushed.
+    next_frame_id: u64,
+}
+
+/// Maximum number of calls recorded in the backtrace of an error.
+const MAX_TRACE: usize = 1000;
+
+/// Bounds on the resources a program may use. Exceeding one raises a `LimitExceeded` error.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Maximum number of nested calls.
+    pub max_call_depth: usize,
+    /// Maximum nesting of `exec_ops`, which primitives may use to execute code on the Rust stack.
+    pub max_nesting: usize,
+    pub max_main_stack: usize,
+    pub max_secondary_stack: usize,
+    /// Number of ops that may still be executed, or `None` for no limit.
+    pub fuel: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_call_depth: 1_000_000,
+            max_nesting: 250,
+            max_main_stack: 1_000_000,
+            max_secondary_stack: 1_000_000,
+            fuel: None,
+        }
+    }
+}
+
+/// Code being executed, and the index of the next instruction.
+struct Frame {
+    id: u64,
+    code: Arc<Code>,
+    pc: usize,
+    /// Index of the instruction that is currently executed.
+    current: usize,
+    /// The word whose method is executed in this frame or, for blocks, the enclosing word.
+    word: Option<(Symbol, Arc<StackEffect>)>,
+    is_block: bool,
+    /// Whether the word returns when this frame is exhausted. This is the case for method bodies
+    /// and for blocks that replaced a method body by a tail call.
+    ends_word: bool,
+    /// Stack effects to verify in strict mode when the frame is exhausted, innermost last. A frame
+    /// that replaces others by a tail call inherits their contracts.
+    contracts: Vec<Contract>,
+    /// Values of the locals declared in the code and, for closures, of those it captured.
+    locals: Vec<Locals>,
+    /// For the body of `try`, what to do if it or a frame above it fails.
+    handler: Option<Handler>,
+}
+
+/// The error handler of a `try` whose body is running.
+struct Handler {
+    ops: Quotation,
+    /// The main and the secondary stack when the body was entered.
+    stacks: (Vec<Value>, Vec<Value>),
+}
+
+/// A method call whose declared stack effect is verified when it returns.
+struct Contract {
+    word: Symbol,
+    effect: Arc<StackEffect>,
+    /// Depth of the main stack below the arguments of the call.
+    base: usize,
+    call_site: Span,
+}
+
+impl Frame {
+    fn new(code: Arc<Code>, word: Option<(Symbol, Arc<StackEffect>)>, is_block: bool) -> Self {
+        Frame {
+            id: 0,
+            code,
+            pc: 0,
+            current: 0,
+            word,
+            is_block,
+            ends_word: !is_block,
+            contracts: vec![],
+            locals: vec![],
+            handler: None,
+        }
+    }
+
+    fn trace_entry(&self) -> TraceEntry {
+        TraceEntry {
+            word: self.word.as_ref().map(|(name, _)| *name),
+            effect: self.word.as_ref().map(|(_, effect)| effect.clone()),
+            is_block: self.is_block,
+            op_index: self.current,
+            span: self.code.span(self.current),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Binding {
-    Primitive(fn(&mut Interpreter) -> Result<()>),
-    Composite(Arc<RwLock<Vec<Method>>>),
+    /// A word implemented in Rust, with its declared stack effect.
+    Primitive(fn(&mut Interpreter) -> Result<()>, Arc<StackEffect>),
+    Composite(Arc<RwLock<Methods>>),
 }
 
 #[derive(Debug)]
 pub struct Method {
     pub effect: Arc<StackEffect>,
     pub doc: Arc<String>,
-    pub body: Arc<[Op]>,
+    pub body: Arc<Block>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub enum Op {
     Literal(Value),
-    Symbol(Symbol),
+    Symbol(Symbol, Span),
     Tuple(usize),
-    Select(usize),
-    If,
+    Select(usize, Span),
+    /// Declare local variables, e.g. `{ a b -- }`.
+    Locals(Arc<[Symbol]>, Span),
+    If(Span),
+    Try(Span),
 
-    BeginDef,
-    BeginTypeDef,
-    End,
+    BeginDef(Span),
+    BeginTypeDef(Span),
+    End(Span),
 
     Effect(Arc<StackEffect>),
 }
 
+/// Ops are equal if they do the same, no matter where in the source code they came from.
+impl PartialEq for Op {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Op::Literal(a), Op::Literal(b)) => a == b,
+            (Op::Symbol(a, _), Op::Symbol(b, _)) => a == b,
+            (Op::Tuple(a), Op::Tuple(b)) => a == b,
+            (Op::Select(a, _), Op::Select(b, _)) => a == b,
+            (Op::Locals(a, _), Op::Locals(b, _)) => a == b,
+            (Op::Effect(a), Op::Effect(b)) => a == b,
+            (Op::If(_), Op::If(_))
+            | (Op::Try(_), Op::Try(_))
+            | (Op::BeginDef(_), Op::BeginDef(_))
+            | (Op::BeginTypeDef(_), Op::BeginTypeDef(_))
+            | (Op::End(_), Op::End(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Op {
+    /// Where in the source code this op came from, if it was parsed.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Op::Symbol(_, span)
+            | Op::Select(_, span)
+            | Op::Locals(_, span)
+            | Op::If(span)
+            | Op::Try(span)
+            | Op::BeginDef(span)
+            | Op::BeginTypeDef(span)
+            | Op::End(span) => Some(span.clone()),
+            Op::Literal(_) | Op::Tuple(_) | Op::Effect(_) => None,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct StackEffect {
     pub pre: Vec<Symbol>,
     pub post: Vec<Symbol>,
 }
 
+impl StackEffect {
+    /// Whether the effect depends on the values on the stack, which is declared with `...`,
+    /// e.g. `( ... Ops -- ... )`.
+    pub fn is_variable(&self) -> bool {
+        let dots = Symbol::from_static("...");
+        self.pre.contains(&dots) || self.post.contains(&dots)
+    }
+
+    /// The values the word expects on top of the stack, without the `...` that stands for the
+    /// values below them in a variable effect.
+    pub fn args(&self) -> &[Symbol] {
+        match self.pre.split_first() {
+            Some((first, args)) if *first == Symbol::from_static("...") => args,
+            _ => &self.pre,
+        }
+    }
+
+    /// Whether the word never returns normally, which is declared with `*`, e.g. `( Str -- * )`.
+    pub fn diverges(&self) -> bool {
+        self.post.contains(&Symbol::from_static("*"))
+    }
+}
+
 impl Default for Interpreter {
     fn default() -> Self {
-        let mut intp = Interpreter {
-            enable_log: false,
-            main_stack: vec![],
-            secondary_stack: vec![],
-            env: default_env(),
-        };
+        let mut intp = Interpreter::new();
+        intp.enable_log = false;
+        intp.env = default_env();
         intp.restore();
         intp.enable_log = true;
         intp
@@ -74,92 +265,586 @@ impl Interpreter {
             main_stack: vec![],
             secondary_stack: vec![],
             env: Default::default(),
+            families: Families::default(),
+            frames: vec![],
+            nesting: 0,
+            limits: Limits::default(),
+            dispatch: DispatchPolicy::default(),
+            effect_check: EffectCheck::default(),
+            strict: false,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            last_error: None,
+            debugger: Debugger::default(),
+            tracer: None,
+            profiler: None,
+            next_frame_id: 0,
         }
     }
 
-    pub fn exec(&mut self, ops: &[Op]) -> Result<()> {
-        let mut ops = ops.iter();
-        while let Some(op) = ops.next() {
-            match op {
-                Op::Literal(value) => self.main_stack.push(value.clone()),
-                Op::Symbol(name) => match self.lookup(*name)? {
-                    Binding::Primitive(prim) => prim(self)?,
-                    Binding::Composite(methods) => {
-                        let body = self
-                            .find_matching_method(&*methods.read().unwrap())
-                            .map_err(|e| format!("{e} for {name}"))?;
-                        self.exec(&body)?;
-                    }
-                },
-                Op::Tuple(n) => {
-                    let mut tuple = vec![Value::Int(0); *n];
-                    tuple[0] = self.pop()?;
-                    for i in (1..*n).rev() {
-                        tuple[i] = self.pop()?;
+    /// A flag that aborts the running evaluation with an `Interrupted` error when it is set.
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    /// Execute ops to completion.
+    pub fn exec(&mut self, ops: impl Into<Arc<[Op]>>) -> Result<()> {
+        self.exec_ops(&Quotation::Block(Arc::new(Block::new(ops))))
+    }
+
+    /// Execute a quotation to completion.
+    pub fn exec_ops(&mut self, ops: &Quotation) -> Result<()> {
+        if self.nesting >= self.limits.max_nesting {
+            return Err(ErrorKind::LimitExceeded(Limit::Nesting).into());
+        }
+        self.check_iteration()?;
+        let base = self.frames.len();
+        let frame = self.quotation_frame(ops, base > 0);
+        self.enter_frame(frame);
+        self.nesting += 1;
+        let result = self.run(base);
+        self.nesting -= 1;
+        if self.nesting == 0 {
+            if let Some(tracer) = &mut self.tracer {
+                if let Err(e) = tracer.flush() {
+                    println!("WARNING: could not write trace: {e}");
+                }
+            }
+        }
+        if result.is_err() {
+            self.frames.truncate(base);
+        }
+        result
+    }
+
+    /// Schedule a block to run next. Primitives use this to execute code without nesting the
+    /// run loop. If the current frame has no ops left, it is replaced, which makes tail calls run
+    /// in constant space.
+    pub fn call(&mut self, ops: &Quotation) {
+        let frame = self.quotation_frame(ops, true);
+        self.push_frame(frame);
+    }
+
+    /// Schedule instructions to run next, like `call`. Combinators use this to interleave calls
+    /// of quotations with pushing values.
+    pub(crate) fn call_instrs(&mut self, instrs: Vec<Instr>) {
+        let word = self.frames.last().and_then(|frame| frame.word.clone());
+        let frame = Frame::new(Arc::new(Code::synthetic(instrs)), word, true);
+        self.push_frame(frame);
+    }
+
+    /// A frame that executes a quotation on behalf of the current word.
+    fn quotation_frame(&self, ops: &Quotation, is_block: bool) -> Frame {
+        let word = self.frames.last().and_then(|frame| frame.word.clone());
+        let mut frame = Frame::new(ops.code(&self.env), word, is_block);
+        frame.locals = ops.locals().to_vec();
+        frame
+    }
+
+    fn call_method(
+        &mut self,
+        name: Symbol,
+        effect: Arc<StackEffect>,
+        body: Arc<Code>,
+        call_site: Span,
+    ) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record_call(name, &effect);
+        }
+        let mut frame = Frame::new(body, Some((name, effect.clone())), false);
+        if self.strict {
+            frame.contracts.push(Contract {
+                word: name,
+                base: self.main_stack.len() - effect.args().len(),
+                effect,
+                call_site,
+            });
+        }
+        self.push_frame(frame);
+    }
+
+    fn push_frame(&mut self, mut frame: Frame) {
+        if let Some(top) = self.frames.last() {
+            // the body of a `try` must stay to catch errors of the frames it calls
+            if top.code.is_done(top.pc) && top.handler.is_none() {
+                frame.ends_word |= frame.is_block && top.ends_word;
+                let mut top = self.frames.pop().unwrap();
+                if !top.contracts.is_empty() {
+                    // a tail-recursive loop would otherwise pile up the same contract
+                    if let (Some(outer), Some(inner)) =
+                        (top.contracts.last(), frame.contracts.last())
+                    {
+                        if outer.word == inner.word
+                            && outer.base == inner.base
+                            && Arc::ptr_eq(&outer.effect, &inner.effect)
+                        {
+                            frame.contracts.pop();
+                        }
                     }
-                    self.push(Value::Tuple(tuple.into()));
+                    top.contracts.append(&mut frame.contracts);
+                    frame.contracts = top.contracts;
+                }
+            }
+        }
+        self.enter_frame(frame);
+    }
+
+    fn enter_frame(&mut self, mut frame: Frame) {
+        frame.id = self.next_frame_id;
+        self.next_frame_id += 1;
+        self.frames.push(frame);
+    }
+
+    /// The current call stack, most recent call first.
+    pub fn backtrace(&self) -> Vec<TraceEntry> {
+        self.frames.iter().rev().map(Frame::trace_entry).collect()
+    }
+
+    /// Execute instructions until the frame stack shrinks to `base` frames.
+    fn run(&mut self, base: usize) -> Result<()> {
+        loop {
+            match self.run_frames(base) {
+                Ok(()) => return Ok(()),
+                Err(e) => self.catch(e, base)?,
+            }
+        }
+    }
+
+    /// Let the innermost `try` above `base` handle an error: drop the frames of its body,
+    /// restore the stacks to the state they had before the body and schedule the handler.
+    fn catch(&mut self, e: Error, base: usize) -> Result<()> {
+        if e.kind == ErrorKind::Interrupted {
+            return Err(e);
+        }
+        let Some(index) = self.frames[base..]
+            .iter()
+            .rposition(|f| f.handler.is_some())
+        else {
+            return Err(e);
+        };
+        let body = self.frames.drain(base + index..).next().unwrap();
+        let handler = body.handler.unwrap();
+        (self.main_stack, self.secondary_stack) = handler.stacks;
+        self.push(e.to_value());
+        // the handler takes over the word the body may have replaced by a tail call
+        let mut frame = self.quotation_frame(&handler.ops, true);
+        frame.ends_word = body.ends_word;
+        frame.contracts = body.contracts;
+        self.push_frame(frame);
+        Ok(())
+    }
+
+    fn run_frames(&mut self, base: usize) -> Result<()> {
+        while self.frames.len() > base {
+            let frame = self.frames.last_mut().unwrap();
+            let code = frame.code.clone();
+            let Some(instr) = code.instrs.get(frame.pc) else {
+                let frame = self.frames.pop().unwrap();
+                if let (Some(_), Some((word, _)), true) =
+                    (&self.tracer, &frame.word, frame.ends_word)
+                {
+                    self.trace(|tracer, depth, stacks| tracer.ret(depth, *word, stacks));
                 }
-                Op::Select(i) => {
-                    let top = self.pop()?;
-                    self.push(top.clone());
-                    self.push(top);
-                    let tuple = self.pop_tuple()?;
-                    self.push(tuple[*i].clone());
+                if let Err(e) = self.check_contracts(&frame.contracts) {
+                    return Err(self.annotate(e, None));
                 }
-                Op::If => {
-                    let cond = self.pop_bool()?;
-                    let yes = Self::require_branch(ops.next())?;
-                    let no = Self::require_branch(ops.next())?;
-                    if cond {
-                        self.exec(yes)?;
-                    } else {
-                        self.exec(no)?;
-                    }
+                continue;
+            };
+            let Some(op) = code.op(frame.pc) else {
+                // instructions the user did not write are not traced and consume no fuel
+                frame.pc += 1;
+                if let Err(e) = self.exec_instr(instr) {
+                    return Err(self.annotate(e, None));
                 }
-                Op::End => return Err(format!("Unexpected {}", op)),
-                Op::BeginDef => self.define_word(&mut ops)?,
-                Op::BeginTypeDef => self.define_type(&mut ops)?,
-                _ => todo!("{op:?}"),
+                continue;
+            };
+            frame.current = frame.pc;
+            frame.pc += 1;
+
+            if self.tracer.is_some() {
+                let word = frame.word.as_ref().map(|(name, _)| *name);
+                let overload = self.peek_overload(op);
+                self.trace(|tracer, depth, stacks| tracer.op(depth, word, op, overload, stacks));
+            }
+
+            let sampled_stack = match self.profiler.as_mut().map(Profiler::tick) {
+                Some(true) => Some(self.profiled_stack(instr, op)),
+                _ => None,
+            };
+
+            let result = self
+                .check_stop(op)
+                .and_then(|_| self.check_interrupt())
+                .and_then(|_| self.consume_fuel())
+                .and_then(|_| self.exec_instr(instr))
+                .and_then(|_| self.check_limits());
+
+            if let (Some(profiler), Some(stack)) = (&mut self.profiler, sampled_stack) {
+                profiler.sample(stack);
+            }
+            if let Err(e) = result {
+                return Err(self.annotate(e, op.span()));
             }
         }
         Ok(())
     }
 
-    fn require_branch(op: Option<&Op>) -> Result<&[Op]> {
-        match op {
-            None => Err("Unexpected end".to_string()),
-            Some(Op::Literal(Value::Block(ops))) => Ok(ops),
-            Some(_) => Err("Expected block literal".to_string()),
+    /// The words on the call stack while executing an op, outermost first. A primitive the op
+    /// calls is the innermost word.
+    fn profiled_stack(&self, instr: &Instr, op: &Op) -> Vec<Symbol> {
+        let mut stack: Vec<Symbol> = self
+            .frames
+            .iter()
+            .filter(|frame| frame.ends_word)
+            .filter_map(|frame| frame.word.as_ref().map(|(name, _)| *name))
+            .collect();
+        if let (Instr::Primitive(_), Op::Symbol(name, _)) = (instr, op) {
+            stack.push(*name);
+        }
+        stack
+    }
+
+    /// Attach the call stack and the location where an error occurred.
+    fn annotate(&self, mut e: Error, span: Option<Span>) -> Error {
+        if e.trace.is_empty() {
+            e.trace = self
+                .frames
+                .iter()
+                .rev()
+                .take(MAX_TRACE)
+                .map(Frame::trace_entry)
+                .collect();
+        }
+        match span {
+            Some(span) => e.at(span),
+            None => e,
         }
     }
 
-    fn find_matching_method(&self, methods: &[Method]) -> Result<Arc<[Op]>> {
-        'method: for Method { effect, body, .. } in methods.iter().rev() {
-            if effect.pre.len() > self.main_stack.len() {
+    /// Verify that the methods returning with a frame left the stack as they declared.
+    fn check_contracts(&self, contracts: &[Contract]) -> Result<()> {
+        for contract in contracts.iter().rev() {
+            let effect = &contract.effect;
+            if effect.is_variable() {
                 continue;
             }
+            let violation = |found| {
+                Error::new(ErrorKind::ContractViolation {
+                    word: contract.word,
+                    effect: effect.clone(),
+                    found,
+                })
+                .at(contract.call_site.clone())
+            };
+            if self.main_stack.len() < contract.base {
+                return Err(violation(None));
+            }
+            let results = &self.main_stack[contract.base..];
+            let mismatch = effect.diverges()
+                || results.len() != effect.post.len()
+                || results
+                    .iter()
+                    .zip(&effect.post)
+                    .any(|(value, ty)| ty.is_type() && !self.families.is_a(value.get_type(), *ty));
+            if mismatch {
+                return Err(violation(Some(
+                    results.iter().map(Value::get_type).collect(),
+                )));
+            }
+        }
+        Ok(())
+    }
 
-            for (e, s) in effect.pre.iter().rev().zip(self.main_stack.iter().rev()) {
-                if e.is_type() && s.get_type() != *e {
-                    continue 'method;
+    fn exec_instr(&mut self, instr: &Instr) -> Result<()> {
+        match instr {
+            Instr::Push(value) => self.main_stack.push(value.clone()),
+            Instr::Primitive(prim) => prim(self)?,
+            Instr::Call(methods, name, span) => {
+                let (effect, body) = self.select_method(*name, methods)?;
+                self.call_method(*name, effect, body, span.clone());
+            }
+            Instr::Lookup(name, span) => match self.lookup(*name)? {
+                Binding::Primitive(prim, _) => prim(self)?,
+                Binding::Composite(methods) => {
+                    let (effect, body) = self.select_method(*name, methods)?;
+                    self.call_method(*name, effect, body, span.clone());
+                }
+            },
+            Instr::Tuple(n) => {
+                let mut tuple = vec![Value::Int(0); *n];
+                tuple[0] = self.pop()?;
+                for i in (1..*n).rev() {
+                    tuple[i] = self.pop()?;
+                }
+                self.push(Value::Tuple(tuple.into()));
+            }
+            Instr::Select(i) => {
+                let top = self.pop()?;
+                self.push(top.clone());
+                self.push(top);
+                let tuple = self.pop_tuple()?;
+                let field = tuple.get(*i).ok_or_else(|| ErrorKind::NoSuchField {
+                    field: *i,
+                    found: Value::Tuple(tuple.clone()).get_type(),
+                })?;
+                self.push(field.clone());
+            }
+            Instr::Bind(scope, names) => {
+                if self.main_stack.len() < names.len() {
+                    return Err(ErrorKind::StackUnderflow.into());
                 }
+                let values = self
+                    .main_stack
+                    .split_off(self.main_stack.len() - names.len());
+                let frame = self.frames.last_mut().unwrap();
+                frame.locals.push(Locals {
+                    scope: *scope,
+                    names: names.clone(),
+                    values,
+                });
             }
+            Instr::Local(scope, slot, name) => {
+                let locals = self.find_locals(*scope);
+                let value = locals.ok_or(ErrorKind::UnknownWord(*name))?.values[*slot].clone();
+                self.push(value);
+            }
+            Instr::Capture(block) => {
+                let closure = self.close(block);
+                self.push(closure.into());
+            }
+            Instr::Apply(ops) => self.call(ops),
+            Instr::JumpUnless(target) => {
+                if !self.pop_bool()? {
+                    self.frames.last_mut().unwrap().pc = *target;
+                }
+            }
+            Instr::Jump(target) => self.frames.last_mut().unwrap().pc = *target,
+            Instr::Try(body, handler) => {
+                let mut frame = self.quotation_frame(&self.close(body), true);
+                frame.handler = Some(Handler {
+                    ops: self.close(handler),
+                    stacks: (self.main_stack.clone(), self.secondary_stack.clone()),
+                });
+                self.push_frame(frame);
+            }
+            Instr::Define(ops) => self.define_word(&mut ops.iter())?,
+            Instr::DefineType(ops) => self.define_type(&mut ops.iter())?,
+            Instr::Invalid(message) => return Err(Error::parse(message.clone())),
+        }
+        Ok(())
+    }
 
-            return Ok(body.clone());
+    /// A quotation of a block with the current values of the locals it refers to.
+    fn close(&self, scoped: &ScopedBlock) -> Quotation {
+        if scoped.captures.is_empty() {
+            return Quotation::Block(scoped.block.clone());
+        }
+        let locals = scoped
+            .captures
+            .iter()
+            .filter_map(|scope| self.find_locals(*scope).cloned())
+            .collect();
+        let code = scoped.block.code(&self.env);
+        Quotation::Closure(Arc::new(Closure::capture(
+            scoped.block.clone(),
+            code,
+            locals,
+        )))
+    }
+
+    /// The locals of a scope in the innermost frame that holds them.
+    fn find_locals(&self, scope: ScopeId) -> Option<&Locals> {
+        self.frames
+            .iter()
+            .rev()
+            .flat_map(|frame| frame.locals.iter().rev())
+            .find(|locals| locals.scope == scope)
+    }
+
+    /// Pass control to the debugger if execution should stop before `op`.
+    fn check_stop(&mut self, op: &Op) -> Result<()> {
+        if !self.debugger.is_active() {
+            return Ok(());
+        }
+
+        let depth = self.frames.len();
+        let frame = self.frames.last().unwrap().id;
+        let reason = match op {
+            Op::Symbol(name, _) if self.debugger.breakpoints.contains(name) => {
+                StopReason::Breakpoint(*name)
+            }
+            _ => match self.debugger.mode {
+                StepMode::Into => StopReason::Step,
+                StepMode::Over { depth: d, frame: f }
+                    if depth < d || (depth == d && frame == f) =>
+                {
+                    StopReason::Step
+                }
+                StepMode::Out { depth: d } if depth < d => StopReason::Step,
+                _ => return Ok(()),
+            },
+        };
+
+        let stop = Stop {
+            reason,
+            op: op.clone(),
+            overload: self.peek_overload(op),
+            trace: self.backtrace(),
+        };
+        let mut hook = self.debugger.hook.take().unwrap();
+        let command = hook(self, &stop);
+        self.debugger.hook = Some(hook);
+
+        self.debugger.mode = match command {
+            Command::StepInto => StepMode::Into,
+            Command::StepOver => StepMode::Over { depth, frame },
+            Command::StepOut => {
+                let word_frame = self.frames.iter().rposition(|f| !f.is_block).unwrap_or(0);
+                StepMode::Out {
+                    depth: word_frame + 1,
+                }
+            }
+            Command::Continue => StepMode::Run,
+            Command::Abort => {
+                self.debugger.mode = StepMode::Run;
+                return Err(ErrorKind::Interrupted.into());
+            }
+        };
+        Ok(())
+    }
+
+    /// Write to the trace, indenting by the depth of the current frame. Tracing stops if the trace
+    /// cannot be written.
+    fn trace(
+        &mut self,
+        write: impl FnOnce(&mut Tracer, usize, (&[Value], &[Value])) -> std::io::Result<()>,
+    ) {
+        let depth = self.frames.len().saturating_sub(1);
+        let tracer = self.tracer.as_mut().unwrap();
+        if let Err(e) = write(tracer, depth, (&self.main_stack, &self.secondary_stack)) {
+            println!("WARNING: could not write trace: {e}");
+            self.tracer = None;
+        }
+    }
+
+    /// The signature of the method `op` would call with the current stack.
+    fn peek_overload(&self, op: &Op) -> Option<Arc<StackEffect>> {
+        match op {
+            Op::Symbol(name, _) => match self.env.get(name) {
+                Some(Binding::Composite(methods)) => {
+                    let methods = methods.read().unwrap();
+                    let method = self.find_matching_method(*name, &methods).ok()?;
+                    Some(method.effect.clone())
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn check_interrupt(&self) -> Result<()> {
+        if self.interrupt.load(Ordering::Relaxed) {
+            self.interrupt.store(false, Ordering::Relaxed);
+            return Err(ErrorKind::Interrupted.into());
+        }
+        Ok(())
+    }
+
+    /// Account for one iteration of a loop or one nested execution, which may not consist of any
+    /// op the user wrote, and let Ctrl-C interrupt it.
+    pub(crate) fn check_iteration(&mut self) -> Result<()> {
+        self.check_interrupt()?;
+        self.consume_fuel()?;
+        self.check_limits()
+    }
+
+    fn consume_fuel(&mut self) -> Result<()> {
+        match &mut self.limits.fuel {
+            None => Ok(()),
+            Some(0) => Err(ErrorKind::LimitExceeded(Limit::Fuel).into()),
+            Some(fuel) => {
+                *fuel -= 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn check_limits(&self) -> Result<()> {
+        let exceeded = if self.frames.len() > self.limits.max_call_depth {
+            Limit::CallDepth
+        } else if self.main_stack.len() > self.limits.max_main_stack {
+            Limit::MainStack
+        } else if self.secondary_stack.len() > self.limits.max_secondary_stack {
+            Limit::SecondaryStack
+        } else {
+            return Ok(());
+        };
+        Err(ErrorKind::LimitExceeded(exceeded).into())
+    }
+
+    /// The effect and compiled body of the method of a composite word that accepts the stack.
+    fn select_method(
+        &self,
+        name: Symbol,
+        methods: &RwLock<Methods>,
+    ) -> Result<(Arc<StackEffect>, Arc<Code>)> {
+        let read = methods.read().unwrap();
+        if let Some(method) = read.cached(self.dispatch, &self.main_stack) {
+            return Ok((method.effect.clone(), method.body.code(&self.env)));
+        }
+        let method = self.find_matching_method(name, &read)?;
+        let (effect, body) = (method.effect.clone(), method.body.code(&self.env));
+        let (index, count) = (read.index_of(method), read.len());
+        drop(read);
+        if let Some(index) = index {
+            let mut write = methods.write().unwrap();
+            write.remember(self.dispatch, &self.main_stack, index, count);
+        }
+        Ok((effect, body))
+    }
+
+    fn find_matching_method<'m>(&self, name: Symbol, methods: &'m [Method]) -> Result<&'m Method> {
+        let accepts = |effect: &StackEffect| {
+            effect.args().len() <= self.main_stack.len()
+                && effect
+                    .args()
+                    .iter()
+                    .rev()
+                    .zip(self.main_stack.iter().rev())
+                    .all(|(e, s)| !e.is_type() || self.families.is_a(s.get_type(), *e))
+        };
+        let found = |methods: &mut dyn Iterator<Item = &Method>| {
+            let arity = methods.map(|m| m.effect.args().len()).max().unwrap_or(0);
+            self.main_stack[self.main_stack.len().saturating_sub(arity)..]
+                .iter()
+                .map(Value::get_type)
+                .collect()
+        };
+        match self.dispatch.select(methods, &self.families, accepts) {
+            Selection::Found(method) => Ok(method),
+            Selection::NoMatch => Err(ErrorKind::NoMatchingMethod {
+                word: name,
+                found: found(&mut methods.iter()),
+            }
+            .into()),
+            Selection::Ambiguous(candidates) => Err(ErrorKind::AmbiguousMethod {
+                word: name,
+                found: found(&mut candidates.iter().copied()),
+                candidates: candidates.iter().map(|m| m.effect.clone()).collect(),
+            }
+            .into()),
         }
-        Err(format!("found no matching method"))
     }
 
     pub fn lookup(&self, name: Symbol) -> Result<&Binding> {
         self.env
             .get(&name)
-            .ok_or_else(|| format!("Unknown {}", name))
+            .ok_or(Error::new(ErrorKind::UnknownWord(name)))
     }
 
     pub fn pop(&mut self) -> Result<Value> {
         self.main_stack
             .pop()
-            .ok_or_else(|| format!("Pop from empty stack"))
+            .ok_or(Error::new(ErrorKind::StackUnderflow))
     }
 
     pub fn pop_bool(&mut self) -> Result<bool> {
@@ -186,7 +871,7 @@ impl Interpreter {
         self.pop()?.expect_tuple()
     }
 
-    pub fn pop_ops(&mut self) -> Result<Arc<[Op]>> {
+    pub fn pop_ops(&mut self) -> Result<Quotation> {
         self.pop()?.expect_block()
     }
 
@@ -213,27 +898,30 @@ impl Interpreter {
 
     fn parse_func<'a>(&self, ops: &mut impl Iterator<Item = &'a Op>) -> Result<(Symbol, Method)> {
         let name = match ops.next() {
-            Some(Op::Symbol(Symbol(name))) if name.starts_with(':') || name.starts_with('%') => {
-                return Err(format!("User definitions may not start with : or %"))
+            Some(Op::Symbol(Symbol(name), _)) if name.starts_with(':') || name.starts_with('%') => {
+                return Err(Error::invalid_definition(
+                    "User definitions may not start with : or %",
+                ))
             }
-            Some(Op::Symbol(name)) => *name,
-            _ => return Err(format!("Expected name")),
-        };
-
-        let effect = match ops.next() {
-            Some(Op::Effect(effect)) => effect.clone(),
-            _ => return Err(format!("Expected stack effect declaration")),
+            Some(Op::Symbol(name, _)) => *name,
+            _ => return Err(Error::parse("Expected name")),
         };
 
         let mut body = vec![];
         loop {
             match ops.next() {
-                None => return Err(format!("Undelimited function definition")),
-                Some(Op::End) => break,
+                None => return Err(Error::parse("Undelimited function definition")),
+                Some(Op::End(_)) => break,
                 Some(op) => body.push(op.clone()),
             }
         }
 
+        let mut effect = None;
+        if let Some(Op::Effect(e)) = body.first() {
+            effect = Some(e.clone());
+            body.remove(0);
+        }
+
         let doc;
         if let Some(Op::Literal(Value::Str(d))) = body.first() {
             doc = d.clone();
@@ -242,63 +930,128 @@ impl Interpreter {
             doc = Arc::new("".to_string());
         }
 
+        let effect = match effect {
+            Some(effect) => effect,
+            None => Arc::new(infer_effect(
+                &self.env,
+                &self.families,
+                self.dispatch,
+                name,
+                &body,
+                None,
+            )?),
+        };
+
         Ok((
             name,
             Method {
                 effect,
                 doc,
-                body: body.into(),
+                body: Arc::new(Block::new(body)),
             },
         ))
     }
 
     fn define_type<'a>(&mut self, ops: &mut impl Iterator<Item = &'a Op>) -> Result<()> {
-        let name = match ops.next() {
-            Some(Op::Symbol(Symbol(name))) if name.starts_with(':') || name.starts_with('%') || !name.starts_with(char::is_uppercase) => {
-                return Err(format!("Type definitions may not start with : or % and must start with an upper case letter"))
-            }
-            Some(Op::Symbol(name)) => *name,
-            _ => return Err(format!("Expected type name")),
-        };
+        let name = type_name(ops.next(), "Expected type name")?;
 
-        let mut doc = None;
-
-        let mut types = vec![];
+        let mut body = vec![];
         loop {
             match ops.next() {
-                None => return Err(format!("Undelimited type definition")),
-                Some(Op::End) => break,
-                Some(Op::Literal(Value::Str(d))) => doc = Some(d.clone()),
-                Some(Op::Symbol(ty)) => types.push(*ty),
-                Some(other) => return Err(format!("Invalid type {other:?}")),
+                None => return Err(Error::parse("Undelimited type definition")),
+                Some(Op::End(_)) => break,
+                Some(op) => body.push(op.clone()),
             }
         }
 
-        let doc = doc.unwrap_or_else(|| Arc::new("".to_string()));
+        let is = |op: &Op, sym| matches!(op, Op::Symbol(s, _) if *s == Symbol::from_static(sym));
+        let Some(equals) = body.iter().position(|op| is(op, "=")) else {
+            let (doc, types) = fields(&body)?;
+            let doc = doc.unwrap_or_else(|| Arc::new("".to_string()));
 
-        let mut logline = format!(":t {name} {doc:?} ");
-        for ty in &types {
-            logline = format!("{logline} {ty}")
+            let mut logline = format!(":t {name} {} ", QuotedStr(&doc));
+            for ty in &types {
+                logline = format!("{logline} {ty}")
+            }
+            logline += " ;";
+
+            self.define_constructor(name, doc, types)?;
+            self.log(&logline);
+            return Ok(());
+        };
+
+        // a sum type: `:t Family = Variant field... | Variant field... ;`
+        let (doc, types) = fields(&body[..equals])?;
+        if let Some(ty) = types.first() {
+            return Err(Error::parse(format!(
+                "Sum type {name} has no fields of its own, found {ty}"
+            )));
+        }
+        let mut variants = vec![];
+        for ops in body[equals + 1..].split(|op| is(op, "|")) {
+            let variant = type_name(ops.first(), "Expected variant name")?;
+            if variants.iter().any(|(v, _, _)| *v == variant) {
+                return Err(Error::invalid_definition(format!(
+                    "Variant {variant} of {name} is declared twice"
+                )));
+            }
+            let (variant_doc, types) = fields(&ops[1..])?;
+            let doc = variant_doc
+                .or_else(|| doc.clone())
+                .unwrap_or_else(|| Arc::new("".to_string()));
+            variants.push((variant, doc, types));
+        }
+
+        for (variant, doc, types) in &variants {
+            self.define_constructor(*variant, doc.clone(), types.clone())?;
+        }
+        self.families
+            .define(name, variants.into_iter().map(|(v, _, _)| v).collect());
+        // methods that were selected for a variant may now accept it as a member of the family
+        for binding in self.env.values() {
+            if let Binding::Composite(methods) = binding {
+                methods.write().unwrap().clear_cache();
+            }
         }
-        logline += " ;";
 
+        self.log(&format!(":t {name} {} ;", DisplayBlock(&body)));
+
+        Ok(())
+    }
+
+    /// Define the constructor of a tuple type, replacing all methods of the word.
+    fn define_constructor(
+        &mut self,
+        name: Symbol,
+        doc: Arc<String>,
+        types: Vec<Symbol>,
+    ) -> Result<()> {
         let method = Method {
-            body: vec![Op::Literal(Value::Symbol(name)), Op::Tuple(types.len() + 1)].into(),
+            body: Arc::new(Block::new(vec![
+                Op::Literal(Value::Symbol(name)),
+                Op::Tuple(types.len() + 1),
+            ])),
             doc,
             effect: Arc::new(StackEffect {
-                pre: types.clone(),
+                pre: types,
                 post: vec![name],
             }),
         };
 
-        self.env.insert(
-            name,
-            Binding::Composite(Arc::new(RwLock::new(vec![method]))),
-        );
-
-        self.log(&logline);
-
-        Ok(())
+        match self
+            .env
+            .entry(name)
+            .or_insert(Binding::Composite(Default::default()))
+        {
+            Binding::Primitive(..) => Err(Error::invalid_definition(format!(
+                "cannot redefine primitive {name}"
+            ))),
+            // a type definition replaces all methods of the constructor
+            Binding::Composite(methods) => {
+                *methods.write().unwrap() = Methods::from(vec![method]);
+                Ok(())
+            }
+        }
     }
 
     pub fn log(&self, line: &str) {
@@ -331,29 +1084,93 @@ impl Interpreter {
             }
         };
 
-        let ops = parse(&src).unwrap();
-        self.exec(&ops).unwrap();
+        if let Err(e) = parse("session.forth", &src).and_then(|ops| self.exec(ops)) {
+            println!("Error while restoring session: {e}");
+            print!("{}", e.backtrace());
+        }
+    }
+}
+
+/// The name of a type or of a variant of a sum type.
+fn type_name(op: Option<&Op>, expected: &str) -> Result<Symbol> {
+    match op {
+        Some(Op::Symbol(Symbol(name), _)) if name.starts_with(':') || name.starts_with('%') || !name.starts_with(char::is_uppercase) => {
+            Err(Error::invalid_definition("Type definitions may not start with : or % and must start with an upper case letter"))
+        }
+        Some(Op::Symbol(name, _)) => Ok(*name),
+        _ => Err(Error::parse(expected)),
     }
 }
 
+/// The doc string and the field types of a tuple type.
+fn fields(ops: &[Op]) -> Result<(Option<Arc<String>>, Vec<Symbol>)> {
+    let mut doc = None;
+    let mut types = vec![];
+    for op in ops {
+        match op {
+            Op::Literal(Value::Str(d)) => doc = Some(d.clone()),
+            Op::Symbol(ty, _) => types.push(*ty),
+            other => return Err(Error::parse(format!("Invalid type {other}"))),
+        }
+    }
+    Ok((doc, types))
+}
+
 impl ExecutionContext for Interpreter {
     fn define_word<'a>(&mut self, ops: &mut impl Iterator<Item = &'a Op>) -> Result<()> {
         let (name, method) = self.parse_func(ops)?;
+        check_patterns(&method.body)?;
+
+        if self.effect_check != EffectCheck::Off {
+            if let Err(e) = check_method(&self.env, &self.families, self.dispatch, name, &method) {
+                if self.effect_check == EffectCheck::Reject {
+                    return Err(e);
+                }
+                println!("WARNING: {e}");
+            }
+        }
+
+        for (family, missing) in uncovered_variants(&method.body, &self.families) {
+            let missing: Vec<_> = missing.iter().map(Symbol::to_string).collect();
+            println!(
+                "WARNING: match in {name} does not cover {} of {family}",
+                missing.join(", ")
+            );
+        }
 
         let logline = match self
             .env
             .entry(name)
-            .or_insert(Binding::Composite(Arc::new(RwLock::new(vec![]))))
+            .or_insert(Binding::Composite(Default::default()))
         {
-            Binding::Primitive(_) => return Err(format!("cannot redefine primitive {name}")),
+            Binding::Primitive(..) => {
+                return Err(Error::invalid_definition(format!(
+                    "cannot redefine primitive {name}"
+                )))
+            }
             Binding::Composite(methods) => {
-                let logline = format!(
-                    ": {} {} {} ;",
-                    name,
-                    method.effect,
-                    DisplayBlock(&method.body)
-                );
-                methods.write().unwrap().push(method);
+                let mut logline = format!(": {} {} ", name, method.effect);
+                if !method.doc.is_empty()
+                    || matches!(method.body.first(), Some(Op::Literal(Value::Str(_))))
+                {
+                    logline += &format!("{} ", QuotedStr(&method.doc));
+                }
+                logline += &format!("{} ;", DisplayBlock(&method.body));
+                let mut methods = methods.write().unwrap();
+                for old in newly_shadowed(&methods, &method.effect, self.dispatch, &self.families) {
+                    if same_signature(&old.effect, &method.effect) {
+                        println!(
+                            "WARNING: {name} {} replaces {name} {}, which has the same signature",
+                            method.effect, old.effect
+                        );
+                    } else {
+                        println!(
+                            "WARNING: {name} {} shadows {name} {}, which can no longer be called",
+                            method.effect, old.effect
+                        );
+                    }
+                }
+                methods.push(method);
                 logline
             }
         };
@@ -363,3 +1180,133 @@ impl ExecutionContext for Interpreter {
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// An interpreter with the primitives only, which does not read or write the session file.
+    pub(crate) fn interpreter() -> Interpreter {
+        let mut intp = Interpreter::new();
+        intp.enable_log = false;
+        intp.env = default_env();
+        intp
+    }
+
+    /// Execute code and take the values it leaves on the stack.
+    pub(crate) fn run(intp: &mut Interpreter, src: &str) -> Vec<Value> {
+        intp.exec(parse("<test>", src).unwrap()).unwrap();
+        std::mem::take(&mut intp.main_stack)
+    }
+
+    #[test]
+    fn selected_methods_follow_new_families() {
+        let mut intp = interpreter();
+        run(
+            &mut intp,
+            ":t Circle Flt ; \
+             : area ( x -- Int ) %drop 0 ; \
+             : area ( Shape -- Int ) %drop 1 ;",
+        );
+        assert_eq!(run(&mut intp, "1.0 Circle area"), [Value::Int(0)]);
+
+        run(&mut intp, ":t Shape = Circle Flt | Square Flt ;");
+        assert_eq!(run(&mut intp, "1.0 Circle area"), [Value::Int(1)]);
+    }
+
+    #[test]
+    fn selected_methods_follow_new_methods() {
+        let mut intp = interpreter();
+        run(&mut intp, ": f ( x -- Int ) %drop 0 ;");
+        assert_eq!(run(&mut intp, "1 f"), [Value::Int(0)]);
+
+        run(&mut intp, ": f ( Int -- Int ) %drop 1 ;");
+        assert_eq!(run(&mut intp, "1 f"), [Value::Int(1)]);
+    }
+
+    #[test]
+    fn try_resets_the_stacks_and_calls_the_handler() {
+        let mut intp = interpreter();
+        let src = "1 try [ 2 3 %>> 4 \"oops\" %throw ] [ ]";
+        let oops = Value::Str(Arc::new("oops".to_string()));
+        assert_eq!(run(&mut intp, src), [Value::Int(1), oops]);
+        assert!(intp.secondary_stack.is_empty());
+    }
+
+    #[test]
+    fn try_restores_values_the_body_consumed() {
+        let mut intp = interpreter();
+        let src = "1 2 3 %>> try [ %drop %drop %<< %drop \"x\" %throw ] [ ]";
+        let x = Value::Str(Arc::new("x".to_string()));
+        assert_eq!(run(&mut intp, src), [Value::Int(1), Value::Int(2), x]);
+        assert_eq!(intp.secondary_stack, [Value::Int(3)]);
+    }
+
+    #[test]
+    fn errors_in_the_handler_reach_the_enclosing_try() {
+        let mut intp = interpreter();
+        let src = "try [ try [ 1 %throw ] [ 2 %ii+ %throw ] ] [ ] 10";
+        assert_eq!(run(&mut intp, src), [Value::Int(3), Value::Int(10)]);
+    }
+
+    #[test]
+    fn loops_and_try_do_not_nest_the_run_loop() {
+        let mut intp = interpreter();
+        let depth = intp.limits.max_nesting + 50;
+        run(
+            &mut intp,
+            ": t ( Int -- Int ) %dup 0 %ii= if [ ] [ [ 1 %ii- t ] 1 %times ] ; \
+             : c ( Int -- Int ) try [ %dup 0 %ii= if [ ] [ 1 %ii- c ] ] [ %throw ] ;",
+        );
+        assert_eq!(run(&mut intp, &format!("{depth} t")), [Value::Int(0)]);
+        assert_eq!(run(&mut intp, &format!("{depth} c")), [Value::Int(0)]);
+    }
+
+    fn fails(intp: &mut Interpreter, src: &str) -> ErrorKind {
+        let e = intp.exec(parse("<test>", src).unwrap()).unwrap_err();
+        intp.main_stack.clear();
+        e.kind
+    }
+
+    #[test]
+    fn loops_with_empty_bodies_consume_fuel() {
+        let mut intp = interpreter();
+        intp.limits.fuel = Some(1000);
+        let out_of_fuel = ErrorKind::LimitExceeded(Limit::Fuel);
+        assert_eq!(fails(&mut intp, "[ ] 1000000000 %times"), out_of_fuel);
+        intp.limits.fuel = Some(1000);
+        assert_eq!(fails(&mut intp, "[ true ] [ ] %while"), out_of_fuel);
+    }
+
+    #[test]
+    fn loops_check_the_stack_limits() {
+        let mut intp = interpreter();
+        intp.limits.max_main_stack = 100;
+        let src = "[ ] 0 1000 %do";
+        assert_eq!(
+            fails(&mut intp, src),
+            ErrorKind::LimitExceeded(Limit::MainStack)
+        );
+    }
+
+    #[test]
+    fn loops_can_be_interrupted() {
+        let mut intp = interpreter();
+        intp.interrupt.store(true, Ordering::Relaxed);
+        assert_eq!(
+            fails(&mut intp, "[ ] 1000000000 %times"),
+            ErrorKind::Interrupted
+        );
+    }
+
+    #[test]
+    fn match_reports_the_value_no_case_applies_to() {
+        let mut intp = interpreter();
+        run(&mut intp, ":t Circle Flt ;");
+        let e = fails(&mut intp, "2.0 Circle [ [ Int ] [ ] ] %match");
+        let message = "none of the cases of match applies to 2.0 Circle ( Circle )";
+        assert_eq!(e.to_string(), message);
+        let e = fails(&mut intp, "[ [ false ] [ 1 ] ] %cond");
+        assert_eq!(e.to_string(), "none of the cases of cond applies");
+    }
+}
@@ -0,0 +1,17 @@
+pub mod closure;
+pub mod compiler;
+mod conversions;
+pub mod debugger;
+mod default_env;
+pub mod dispatch;
+pub mod effects;
+pub mod errors;
+pub mod interpreter;
+pub mod parser;
+pub mod pattern;
+pub mod profiler;
+pub mod serialize;
+pub mod source;
+pub mod symbol;
+pub mod tracer;
+pub mod value;
@@ -1,30 +1,46 @@
-mod conversions;
-mod default_env;
-mod errors;
-mod interpreter;
-mod parser;
-mod serialize;
-mod symbol;
-mod value;
-
-use crate::errors::Result;
-use crate::interpreter::Interpreter;
-use crate::parser::parse;
-use reedline::{DefaultPrompt, Reedline, Signal};
+use forthly::debugger::{Command, Stop};
+use forthly::dispatch::{resolution_order, DispatchPolicy};
+use forthly::effects::{infer_effect, EffectCheck};
+use forthly::errors::{Error, ErrorKind, Result};
+use forthly::interpreter::{Binding, Interpreter};
+use forthly::parser::parse;
+use forthly::profiler::{Profiler, SortBy};
+use forthly::symbol::Symbol;
+use forthly::tracer::Tracer;
+use reedline::{DefaultPrompt, DefaultPromptSegment, Reedline, Signal};
+use signal_hook::consts::SIGINT;
+use std::sync::atomic::Ordering;
 
 fn main() {
     let mut line_editor = Reedline::create();
     let prompt = DefaultPrompt::default();
 
     let mut interpreter = Interpreter::default();
+    interpreter.debugger.set_hook(debug_prompt());
+
+    let interrupt = interpreter.interrupt_flag();
+    if let Err(e) = signal_hook::flag::register(SIGINT, interrupt.clone()) {
+        println!("WARNING: could not install Ctrl-C handler: {e}");
+    }
 
     loop {
         let sig = line_editor.read_line(&prompt);
         match sig {
-            Ok(Signal::Success(buffer)) => match eval(&buffer, &mut interpreter) {
-                Ok(()) => {}
-                Err(e) => println!("Error: {}", e),
-            },
+            Ok(Signal::Success(buffer)) => {
+                interrupt.store(false, Ordering::Relaxed);
+                let result = match repl_command(&buffer, &mut interpreter) {
+                    Some(result) => result,
+                    None => eval(&buffer, &mut interpreter),
+                };
+                match result {
+                    Ok(()) => {}
+                    Err(e) => {
+                        println!("Error: {}", e);
+                        print!("{}", e.backtrace());
+                        interpreter.last_error = Some(e);
+                    }
+                }
+            }
             Ok(Signal::CtrlD) | Ok(Signal::CtrlC) => {
                 println!("\nBye!");
                 break;
@@ -37,6 +53,234 @@ fn main() {
 }
 
 fn eval(src: &str, interpreter: &mut Interpreter) -> Result<()> {
-    let ops = parse(&src)?;
-    interpreter.exec(&ops)
+    let ops = parse("<repl>", src)?;
+
+    let main_stack = interpreter.main_stack.clone();
+    let secondary_stack = interpreter.secondary_stack.clone();
+    let result = interpreter.exec(ops);
+    interpreter.debugger.resume();
+    if let Err(Error {
+        kind: ErrorKind::Interrupted,
+        ..
+    }) = result
+    {
+        // leave the stacks as they were before the interrupted evaluation
+        interpreter.main_stack = main_stack;
+        interpreter.secondary_stack = secondary_stack;
+    }
+    result
+}
+
+/// Handle REPL commands that are not words. Returns `None` if the line is ordinary code.
+fn repl_command(line: &str, interpreter: &mut Interpreter) -> Option<Result<()>> {
+    let line = line.trim();
+    let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    match command {
+        ":break" | ":unbreak" => set_breakpoints(command, args, interpreter),
+        ":step" => {
+            interpreter.debugger.step();
+            return Some(eval(args, interpreter));
+        }
+        ":trace" => return Some(set_trace(args, interpreter)),
+        ":profile" => return Some(profile(args, interpreter)),
+        ":dispatch" => return Some(show_dispatch(args, interpreter)),
+        ":dispatch-policy" => {
+            interpreter.dispatch = match DispatchPolicy::from_name(args.trim()) {
+                Some(policy) => policy,
+                None => {
+                    let usage = "Usage: :dispatch-policy last-defined|most-specific";
+                    return Some(Err(Error::parse(usage)));
+                }
+            }
+        }
+        ":infer" => return Some(infer(args, interpreter)),
+        ":strict" => {
+            interpreter.strict = match args.trim() {
+                "on" => true,
+                "off" => false,
+                _ => return Some(Err(Error::parse("Usage: :strict on|off"))),
+            }
+        }
+        ":check" => {
+            interpreter.effect_check = match args.trim() {
+                "off" => EffectCheck::Off,
+                "warn" => EffectCheck::Warn,
+                "reject" => EffectCheck::Reject,
+                _ => return Some(Err(Error::parse("Usage: :check off|warn|reject"))),
+            }
+        }
+        ":next" | ":finish" | ":continue" | ":abort" => println!("Not stopped in the debugger"),
+        _ => return None,
+    }
+    Some(Ok(()))
+}
+
+/// `:break word...` adds breakpoints, `:unbreak word...` removes them. Without words, `:break`
+/// lists the breakpoints and `:unbreak` removes all of them.
+fn set_breakpoints(command: &str, args: &str, interpreter: &mut Interpreter) {
+    let breakpoints = &mut interpreter.debugger.breakpoints;
+    let words = args.split_whitespace().map(Symbol::new);
+    match command {
+        ":break" if args.trim().is_empty() => {
+            let mut names: Vec<_> = breakpoints.iter().map(Symbol::to_string).collect();
+            names.sort();
+            println!("Breakpoints: {}", names.join(" "));
+        }
+        ":break" => breakpoints.extend(words),
+        _ if args.trim().is_empty() => breakpoints.clear(),
+        _ => {
+            for word in words {
+                breakpoints.remove(&word);
+            }
+        }
+    }
+}
+
+/// `:trace on [word...]` traces to stderr, `:trace file path [word...]` into a file, and
+/// `:trace off` stops tracing. If words are given, only those are traced.
+fn set_trace(args: &str, interpreter: &mut Interpreter) -> Result<()> {
+    let mut args = args.split_whitespace();
+    let tracer = match args.next() {
+        Some("off") => None,
+        Some("on") => Some(Tracer::to_stderr()),
+        Some("file") => {
+            let path = args
+                .next()
+                .ok_or_else(|| Error::parse("Expected file name after :trace file"))?;
+            match Tracer::to_file(path) {
+                Ok(tracer) => Some(tracer),
+                Err(e) => return Err(Error::parse(format!("Could not open {path}: {e}"))),
+            }
+        }
+        _ => return Err(Error::parse("Usage: :trace on|off|file <path> [word...]")),
+    };
+    interpreter.tracer = tracer.map(|tracer| tracer.with_words(args.map(Symbol::new)));
+    Ok(())
+}
+
+/// `:infer word...` shows the declared and the inferred stack effect of each method of the words.
+fn infer(args: &str, interpreter: &Interpreter) -> Result<()> {
+    for name in args.split_whitespace().map(Symbol::new) {
+        match interpreter.lookup(name)? {
+            Binding::Primitive(_, effect) => println!("{name} {effect} is a primitive"),
+            Binding::Composite(methods) => {
+                for method in methods.read().unwrap().iter() {
+                    let inferred = infer_effect(
+                        &interpreter.env,
+                        &interpreter.families,
+                        interpreter.dispatch,
+                        name,
+                        &method.body,
+                        Some(&method.effect),
+                    );
+                    match inferred {
+                        Ok(inferred) => println!("{name} {} infers {inferred}", method.effect),
+                        Err(e) => println!("{name} {}: {e}", method.effect),
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `:dispatch word...` lists the methods of the words in the order in which they are tried, and
+/// which of them can never be selected.
+fn show_dispatch(args: &str, interpreter: &Interpreter) -> Result<()> {
+    for name in args.split_whitespace().map(Symbol::new) {
+        match interpreter.lookup(name)? {
+            Binding::Primitive(_, effect) => println!("{name} {effect} is a primitive"),
+            Binding::Composite(methods) => {
+                let methods = methods.read().unwrap();
+                println!("{name} tries its methods in this order:");
+                for (i, overload) in
+                    resolution_order(&methods, interpreter.dispatch, &interpreter.families)
+                        .iter()
+                        .enumerate()
+                {
+                    print!("{:>4}. {}", i + 1, overload.method.effect);
+                    if let Some(other) = overload.shadowed_by {
+                        print!("  shadowed by {}", other.effect);
+                    }
+                    println!();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `:profile [by column] [folded path] [ code ]` runs code and reports the time spent in each
+/// word, sorted by `name`, `calls`, `incl` (the default) or `excl`. The sampled call stacks can be
+/// saved in the folded format of flamegraph tools. The call stack is sampled every 64 ops, so the
+/// times are estimates.
+fn profile(args: &str, interpreter: &mut Interpreter) -> Result<()> {
+    let usage =
+        || Error::parse("Usage: :profile [by name|calls|incl|excl] [folded <path>] [ code ]");
+    let (options, code) = args.split_once('[').ok_or_else(usage)?;
+    let code = code.trim_end().strip_suffix(']').ok_or_else(usage)?;
+
+    let mut sort = SortBy::Inclusive;
+    let mut folded = None;
+    let mut options = options.split_whitespace();
+    while let Some(option) = options.next() {
+        match (option, options.next()) {
+            ("by", Some(column)) => sort = SortBy::from_name(column).ok_or_else(usage)?,
+            ("folded", Some(path)) => folded = Some(path),
+            _ => return Err(usage()),
+        }
+    }
+
+    interpreter.profiler = Some(Profiler::new());
+    let result = eval(code, interpreter);
+    let mut profiler = interpreter.profiler.take().unwrap();
+    profiler.flush();
+
+    print!("{}", profiler.report(sort));
+    if let Some(path) = folded {
+        if let Err(e) = std::fs::write(path, profiler.folded()) {
+            println!("WARNING: could not write {path}: {e}");
+        }
+    }
+    result
+}
+
+/// Show where execution stopped and let the user decide how to continue.
+fn debug_prompt() -> impl FnMut(&mut Interpreter, &Stop) -> Command {
+    let mut line_editor = Reedline::create();
+    let prompt = DefaultPrompt {
+        left_prompt: DefaultPromptSegment::Basic("debug".to_string()),
+        right_prompt: DefaultPromptSegment::Empty,
+    };
+
+    move |interpreter, stop| {
+        print!("{stop}");
+        print_stacks(interpreter);
+        loop {
+            let line = match line_editor.read_line(&prompt) {
+                Ok(Signal::Success(line)) => line,
+                _ => return Command::Abort,
+            };
+            let line = line.trim();
+            let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            match command {
+                "" | ":step" => return Command::StepInto,
+                ":next" => return Command::StepOver,
+                ":finish" => return Command::StepOut,
+                ":continue" => return Command::Continue,
+                ":abort" => return Command::Abort,
+                ":break" | ":unbreak" => set_breakpoints(command, args, interpreter),
+                ":stacks" => print_stacks(interpreter),
+                ":where" => print!("{stop}"),
+                _ => println!(
+                    "Commands: :step, :next, :finish, :continue, :abort, :break, :unbreak, :stacks, :where"
+                ),
+            }
+        }
+    }
+}
+
+fn print_stacks(interpreter: &Interpreter) {
+    println!("  Main Stack: {:?}", interpreter.main_stack);
+    println!("Second Stack: {:?}", interpreter.secondary_stack);
 }
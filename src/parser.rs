@@ -1,31 +1,37 @@
-use crate::errors::Result;
+use crate::compiler::Block;
+use crate::errors::{Error, Result};
 use crate::interpreter::{Op, StackEffect};
+use crate::serialize::QuotedStr;
+use crate::source::{Source, Span};
 use crate::symbol::Symbol;
 use crate::value::Value;
 use std::sync::Arc;
 
-pub fn parse(src: &str) -> Result<Vec<Op>> {
-    let mut tokens = Tokenizer { input: src };
+/// Parse source code. The `name` identifies the source in error messages.
+pub fn parse(name: &str, src: &str) -> Result<Vec<Op>> {
+    let mut tokens = Tokenizer {
+        source: Source::new(name, src),
+        src,
+        input: src,
+    };
 
     parse_ops(&mut tokens, &[], true)
 }
 
-fn parse_op(token: &str) -> Result<Op> {
+fn parse_op(token: &str, span: Span) -> Result<Op> {
     Ok(match token {
-        ";" => Op::End,
-        ":" => Op::BeginDef,
-        ":t" => Op::BeginTypeDef,
+        ";" => Op::End(span),
+        ":" => Op::BeginDef(span),
+        ":t" => Op::BeginTypeDef(span),
         "true" => Op::Literal(Value::True),
         "false" => Op::Literal(Value::False),
-        "if" => Op::If,
-        _ if token.starts_with('"') => {
-            Op::Literal(Value::Str(token.trim_matches('"').to_string().into()))
-        }
+        "if" => Op::If(span),
+        "try" => Op::Try(span),
         _ if token.starts_with('#') => Op::Select(
-            token
-                .trim_matches('#')
-                .parse()
-                .map_err(|_| "# must be followed by a number (select operator)")?,
+            token.trim_matches('#').parse().map_err(|_| {
+                Error::parse("# must be followed by a number (select operator)").at(span.clone())
+            })?,
+            span,
         ),
         _ => {
             if let Ok(x) = token.parse() {
@@ -33,33 +39,34 @@ fn parse_op(token: &str) -> Result<Op> {
             } else if let Ok(x) = token.parse() {
                 Op::Literal(Value::Flt(x))
             } else {
-                Op::Symbol(Symbol::new(token))
+                Op::Symbol(Symbol::new(token), span)
             }
         }
     })
 }
 
-fn parse_block<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<Op> {
+fn parse_block(tokens: &mut Tokenizer) -> Result<Op> {
     let ops = parse_ops(tokens, &["]"], false)?;
-    Ok(Op::Literal(Value::Block(ops.into())))
+    Ok(Op::Literal(Value::Block(Arc::new(Block::new(ops)))))
 }
 
-fn parse_ops<'a>(
-    tokens: &mut impl Iterator<Item = &'a str>,
-    delimiters: &[&str],
-    accept_eof: bool,
-) -> Result<Vec<Op>> {
+fn parse_ops(tokens: &mut Tokenizer, delimiters: &[&str], accept_eof: bool) -> Result<Vec<Op>> {
     let mut ops = vec![];
     loop {
-        match next_token(tokens) {
-            Err(_) if accept_eof => break,
-            Err(e) => return Err(e),
-            Ok("") => continue,
-            Ok("(") => ops.push(parse_stack_effect(tokens)?),
-            Ok("[") => ops.push(parse_block(tokens)?),
-            Ok(token) if delimiters.contains(&token) => break,
-            Ok(token) => {
-                let op = parse_op(token)?;
+        let (token, span) = match tokens.next() {
+            None if accept_eof => break,
+            None => return Err(tokens.unexpected_eof()),
+            Some(token) => token?,
+        };
+        match token {
+            Token::Str(s) => ops.push(Op::Literal(Value::Str(s.into()))),
+            Token::Word("") => continue,
+            Token::Word("(") => ops.push(parse_stack_effect(tokens)?),
+            Token::Word("{") => ops.push(parse_locals(tokens, span)?),
+            Token::Word("[") => ops.push(parse_block(tokens)?),
+            Token::Word(word) if delimiters.contains(&word) => break,
+            Token::Word(word) => {
+                let op = parse_op(word, span)?;
                 ops.push(op);
             }
         }
@@ -67,10 +74,10 @@ fn parse_ops<'a>(
     Ok(ops)
 }
 
-fn parse_stack_effect<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<Op> {
+fn parse_stack_effect(tokens: &mut Tokenizer) -> Result<Op> {
     let mut pre = vec![];
     loop {
-        match next_token(tokens)? {
+        match next_word(tokens)? {
             "--" => break,
             token => pre.push(Symbol::new(token)),
         }
@@ -78,7 +85,7 @@ fn parse_stack_effect<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<
 
     let mut post = vec![];
     loop {
-        match next_token(tokens)? {
+        match next_word(tokens)? {
             ")" => break,
             token => post.push(Symbol::new(token)),
         }
@@ -87,36 +94,252 @@ fn parse_stack_effect<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<
     Ok(Op::Effect(Arc::new(StackEffect { pre, post })))
 }
 
-fn next_token<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<&'a str> {
-    tokens
-        .next()
-        .ok_or_else(|| format!("Unexpected End of Input"))
+/// Parse `{ name ... -- }`. Words between `--` and `}` are comments, as in stack effects.
+fn parse_locals(tokens: &mut Tokenizer, start: Span) -> Result<Op> {
+    let mut names: Vec<Symbol> = vec![];
+    loop {
+        match next_word(tokens)? {
+            "}" => break,
+            "--" => {
+                while next_word(tokens)? != "}" {}
+                break;
+            }
+            name if !name.starts_with(char::is_lowercase) => {
+                let message = format!("Local {name} must start with a lower case letter");
+                return Err(Error::parse(message).at(start));
+            }
+            name if names.contains(&Symbol::new(name)) => {
+                return Err(Error::parse(format!("Local {name} is declared twice")).at(start));
+            }
+            name => names.push(Symbol::new(name)),
+        }
+    }
+    Ok(Op::Locals(names.into(), start))
+}
+
+fn next_word<'a>(tokens: &mut Tokenizer<'a>) -> Result<&'a str> {
+    match tokens.next() {
+        None => Err(tokens.unexpected_eof()),
+        Some(Err(e)) => Err(e),
+        Some(Ok((Token::Word(word), _))) => Ok(word),
+        Some(Ok((Token::Str(s), span))) => {
+            Err(Error::parse(format!("Unexpected string literal {}", QuotedStr(&s))).at(span))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'i> {
+    Word(&'i str),
+    Str(String),
 }
 
 struct Tokenizer<'i> {
+    source: Arc<Source>,
+    src: &'i str,
     input: &'i str,
 }
 
+impl<'i> Tokenizer<'i> {
+    /// Byte offset of the remaining input in the source.
+    fn offset(&self) -> usize {
+        self.src.len() - self.input.len()
+    }
+
+    fn span_from(&self, start: usize) -> Span {
+        Span::new(self.source.clone(), start, self.offset())
+    }
+
+    fn unexpected_eof(&self) -> Error {
+        Error::parse("Unexpected End of Input").at(self.span_from(self.offset()))
+    }
+
+    /// Advance past whitespace, `\ line comments` and `(* block comments *)`.
+    /// Comment markers are only recognized as whole tokens, so they never clash with `(`.
+    fn skip_whitespace_and_comments(&mut self) -> Result<()> {
+        loop {
+            self.input = self.input.trim_start();
+            if starts_with_token(self.input, "\\") {
+                self.input = match self.input.split_once('\n') {
+                    None => &self.input[self.input.len()..],
+                    Some((_, rest)) => rest,
+                };
+            } else if starts_with_token(self.input, "(*") {
+                self.skip_block_comment()?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Block comments nest. A comment that is not closed is an error.
+    fn skip_block_comment(&mut self) -> Result<()> {
+        let start = self.offset();
+        let mut depth = 0;
+        loop {
+            let input = self.input.trim_start();
+            let (word, rest) = match input.split_once(char::is_whitespace) {
+                None => (input, &input[input.len()..]),
+                Some((w, r)) => (w, r),
+            };
+            self.input = rest;
+            match word {
+                "(*" => depth += 1,
+                "*)" => depth -= 1,
+                "" => {
+                    let span = Span::new(self.source.clone(), start, start + 2);
+                    return Err(Error::parse("Unterminated block comment").at(span));
+                }
+                _ => {}
+            }
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Read the body of a string literal whose opening quote was already consumed.
+    fn lex_string(&mut self) -> Result<String> {
+        let start = self.offset() - 1;
+        let mut chars = self.input.char_indices();
+        let mut string = String::new();
+        while let Some((i, ch)) = chars.next() {
+            match ch {
+                '"' => {
+                    self.input = &self.input[i + 1..];
+                    return Ok(string);
+                }
+                '\\' => match chars.next() {
+                    Some((_, '"')) => string.push('"'),
+                    Some((_, '\\')) => string.push('\\'),
+                    Some((_, 'n')) => string.push('\n'),
+                    Some((_, 't')) => string.push('\t'),
+                    Some((_, 'r')) => string.push('\r'),
+                    Some((_, 'u')) => match lex_unicode_escape(&mut chars) {
+                        Ok(ch) => string.push(ch),
+                        Err(e) => return Err(self.error_at(e, start + 1 + i, &chars)),
+                    },
+                    Some((_, other)) => {
+                        let e = format!("Unknown escape sequence \\{other}");
+                        return Err(self.error_at(e, start + 1 + i, &chars));
+                    }
+                    None => break,
+                },
+                _ => string.push(ch),
+            }
+        }
+        self.input = &self.input[self.input.len()..];
+        Err(Error::parse("Unterminated string literal").at(self.span_from(start)))
+    }
+
+    /// Produce an error for the input between `start` and the position of `rest`.
+    fn error_at(&self, message: String, start: usize, rest: &std::str::CharIndices) -> Error {
+        let end = self.src.len() - rest.as_str().len();
+        Error::parse(message).at(Span::new(self.source.clone(), start, end))
+    }
+}
+
+/// Parse the `{XXXX}` part of a `\u{XXXX}` escape sequence.
+fn lex_unicode_escape(
+    chars: &mut impl Iterator<Item = (usize, char)>,
+) -> std::result::Result<char, String> {
+    if chars.next().map(|(_, ch)| ch) != Some('{') {
+        return Err("Expected { after \\u".to_string());
+    }
+    let mut hex = String::new();
+    loop {
+        match chars.next() {
+            None => return Err("Unterminated unicode escape".to_string()),
+            Some((_, '}')) => break,
+            Some((_, ch)) => hex.push(ch),
+        }
+    }
+    u32::from_str_radix(&hex, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| format!("Invalid unicode escape \\u{{{hex}}}"))
+}
+
+fn starts_with_token(input: &str, token: &str) -> bool {
+    match input.strip_prefix(token) {
+        None => false,
+        Some(rest) => rest.starts_with(char::is_whitespace) || rest.is_empty(),
+    }
+}
+
 impl<'i> Iterator for Tokenizer<'i> {
-    type Item = &'i str;
+    type Item = Result<(Token<'i>, Span)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let input = self.input.trim_start();
+        if let Err(e) = self.skip_whitespace_and_comments() {
+            return Some(Err(e));
+        }
+        let input = self.input;
         if input.is_empty() {
             return None;
         }
 
-        let (token, rest) = if input.starts_with('"') {
-            let idx = 2 + input.strip_prefix('"').unwrap().find('"')?;
-            (&input[..idx], &input[idx..])
-        } else {
-            match input.split_once(char::is_whitespace) {
-                None => (input, &input[0..0]),
-                Some((t, r)) => (t, r),
-            }
+        let start = self.offset();
+
+        if let Some(rest) = input.strip_prefix('"') {
+            self.input = rest;
+            return Some(
+                self.lex_string()
+                    .map(|s| (Token::Str(s), self.span_from(start))),
+            );
+        }
+
+        let (token, rest) = match input.split_once(char::is_whitespace) {
+            None => (input, &input[0..0]),
+            Some((t, r)) => (t, r),
         };
 
         self.input = rest;
-        Some(token)
+        Some(Ok((
+            Token::Word(token),
+            Span::new(self.source.clone(), start, start + token.len()),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ErrorKind;
+
+    fn words(src: &str) -> Vec<String> {
+        parse("<test>", src)
+            .unwrap()
+            .iter()
+            .map(|op| op.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn skips_line_comments() {
+        assert_eq!(words("1 \\ 2 3\n4"), ["1", "4"]);
+        assert_eq!(words("1 \\ no newline"), ["1"]);
+    }
+
+    #[test]
+    fn skips_nested_block_comments() {
+        assert_eq!(words("1 (* 2 (* 3 *) 4 *) 5"), ["1", "5"]);
+        assert_eq!(words("(* spans\nlines *) 1"), ["1"]);
+    }
+
+    #[test]
+    fn block_comment_markers_are_words() {
+        // the markers only count when separated by whitespace
+        assert_eq!(words("1 (*) 2 *)"), ["1", "(*)", "2", "*)"]);
+    }
+
+    #[test]
+    fn reports_unterminated_block_comments() {
+        for src in ["1 (* 2 3", "1 (* 2 (* 3 *) 4"] {
+            let e = parse("<test>", src).unwrap_err();
+            assert!(matches!(e.kind, ErrorKind::Parse(_)));
+            let span = e.span.unwrap();
+            assert_eq!((span.start, span.end), (2, 4));
+        }
     }
 }
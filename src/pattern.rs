@@ -0,0 +1,278 @@
+use crate::dispatch::Families;
+use crate::errors::{Error, Result};
+use crate::interpreter::Op;
+use crate::symbol::Symbol;
+use crate::value::Value;
+
+/// A pattern of `match`, written as the ops of a block:
+/// - `[ _ ]` matches any value.
+/// - `[ 42 ]` matches values equal to the literal.
+/// - `[ Complex ]` matches values of the type. The family of a sum type matches all its variants.
+/// - `[ Complex _ 0.0 ]` matches tuples of the type whose fields match the field patterns, which
+///   are `_`, literals or type names.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pattern {
+    Any,
+    Literal(Value),
+    Type(Symbol),
+    Tuple(Symbol, Vec<Pattern>),
+}
+
+impl Pattern {
+    pub fn parse(ops: &[Op]) -> Result<Self> {
+        match ops {
+            [] => Err(Error::parse("Empty pattern")),
+            [op] => Pattern::parse_one(op),
+            [Op::Symbol(name, _), fields @ ..] if name.is_type() => {
+                let fields = fields
+                    .iter()
+                    .map(Pattern::parse_one)
+                    .collect::<Result<_>>()?;
+                Ok(Pattern::Tuple(*name, fields))
+            }
+            [op, ..] => Err(Error::parse(format!(
+                "Pattern with fields must start with a type, found {op}"
+            ))),
+        }
+    }
+
+    fn parse_one(op: &Op) -> Result<Self> {
+        match op {
+            Op::Literal(value) => Ok(Pattern::Literal(value.clone())),
+            Op::Symbol(name, _) if name.is_type() => Ok(Pattern::Type(*name)),
+            Op::Symbol(name, _) if *name == Symbol::from_static("_") => Ok(Pattern::Any),
+            _ => Err(Error::parse(format!("Invalid pattern {op}"))),
+        }
+    }
+
+    /// The values that replace `value` on the stack if it matches: its fields if the pattern
+    /// destructures it, otherwise the value itself.
+    pub fn bind(&self, value: &Value, families: &Families) -> Option<Vec<Value>> {
+        match (self, value) {
+            (Pattern::Tuple(ty, patterns), Value::Tuple(fields))
+                if fields[0] == Value::Symbol(*ty) && fields.len() == patterns.len() + 1 =>
+            {
+                let fields = &fields[1..];
+                patterns
+                    .iter()
+                    .zip(fields)
+                    .all(|(pattern, field)| pattern.matches(field, families))
+                    .then(|| fields.to_vec())
+            }
+            (Pattern::Tuple(..), _) => None,
+            _ => self.matches(value, families).then(|| vec![value.clone()]),
+        }
+    }
+
+    fn matches(&self, value: &Value, families: &Families) -> bool {
+        match self {
+            Pattern::Any => true,
+            Pattern::Literal(literal) => literal == value,
+            Pattern::Type(ty) => families.is_a(value.get_type(), *ty),
+            Pattern::Tuple(..) => self.bind(value, families).is_some(),
+        }
+    }
+}
+
+/// Parse the patterns of every `match` in the ops, or in blocks within them, whose cases are
+/// written as a block literal right before it, so that malformed patterns are reported when the
+/// code is defined rather than when it runs.
+pub fn check_patterns(ops: &[Op]) -> Result<()> {
+    for (i, op) in ops.iter().enumerate() {
+        let Op::Literal(Value::Block(block)) = op else {
+            continue;
+        };
+        check_patterns(block)?;
+        if let Some(Op::Symbol(name, span)) = ops.get(i + 1) {
+            if *name == Symbol::from_static("match") {
+                // a last case without a body is the default case, not a pattern
+                for case in block.chunks_exact(2) {
+                    if let Op::Literal(Value::Block(pattern)) = &case[0] {
+                        pattern.pattern().map_err(|e| e.at(span.clone()))?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The variants of sum types that a `match` in the ops, or in blocks within them, does not cover,
+/// together with their family. Only cases that are written as a block literal right before
+/// `match` are checked, and only if they have no default case.
+pub fn uncovered_variants(ops: &[Op], families: &Families) -> Vec<(Symbol, Vec<Symbol>)> {
+    let mut uncovered = vec![];
+    for (i, op) in ops.iter().enumerate() {
+        let Op::Literal(Value::Block(block)) = op else {
+            continue;
+        };
+        uncovered.extend(uncovered_variants(block, families));
+        if let Some(Op::Symbol(name, _)) = ops.get(i + 1) {
+            if *name == Symbol::from_static("match") {
+                uncovered.extend(uncovered_cases(block, families));
+            }
+        }
+    }
+    uncovered
+}
+
+fn uncovered_cases(cases: &[Op], families: &Families) -> Vec<(Symbol, Vec<Symbol>)> {
+    if cases.len() % 2 == 1 {
+        return vec![];
+    }
+    let mut mentioned = vec![];
+    let mut covered = vec![];
+    for case in cases.iter().step_by(2) {
+        let Op::Literal(Value::Block(pattern)) = case else {
+            return vec![];
+        };
+        let ty = match pattern.pattern().as_deref() {
+            Ok(Pattern::Any) => return vec![],
+            Ok(Pattern::Type(ty)) => *ty,
+            Ok(Pattern::Tuple(ty, fields)) if fields.iter().all(|f| *f == Pattern::Any) => *ty,
+            Ok(Pattern::Tuple(ty, _)) => {
+                // only covers some values of the variant
+                mentioned.extend(families.family(*ty));
+                continue;
+            }
+            Ok(Pattern::Literal(_)) | Err(_) => continue,
+        };
+        covered.push(ty);
+        mentioned.push(families.family(ty).unwrap_or(ty));
+    }
+
+    let mut uncovered = vec![];
+    for family in mentioned {
+        let Some(variants) = families.variants(family) else {
+            continue;
+        };
+        if covered.contains(&family) || uncovered.iter().any(|(f, _)| *f == family) {
+            continue;
+        }
+        let missing: Vec<_> = variants
+            .iter()
+            .filter(|v| !covered.contains(v))
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            uncovered.push((family, missing));
+        }
+    }
+    uncovered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use std::sync::Arc;
+
+    fn pattern(src: &str) -> Pattern {
+        Pattern::parse(&parse("<test>", src).unwrap()).unwrap()
+    }
+
+    fn tuple(ty: &str, fields: Vec<Value>) -> Value {
+        let mut values = vec![Value::Symbol(Symbol::new(ty))];
+        values.extend(fields);
+        Value::Tuple(Arc::new(values))
+    }
+
+    #[test]
+    fn parses_patterns() {
+        assert_eq!(pattern("_"), Pattern::Any);
+        assert_eq!(pattern("42"), Pattern::Literal(Value::Int(42)));
+        assert_eq!(pattern("Int"), Pattern::Type(Symbol::new("Int")));
+        assert_eq!(
+            pattern("Rect _ 1.0"),
+            Pattern::Tuple(
+                Symbol::new("Rect"),
+                vec![Pattern::Any, Pattern::Literal(Value::Flt(1.0))]
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        for src in ["", "x", "1 2", "Rect x", "_ Int"] {
+            let ops = parse("<test>", src).unwrap();
+            assert!(Pattern::parse(&ops).is_err(), "{src:?} is not a pattern");
+        }
+    }
+
+    #[test]
+    fn bind_leaves_the_value_unless_it_is_destructured() {
+        let families = Families::default();
+        let circle = tuple("Circle", vec![Value::Flt(2.0)]);
+        let bind = |src, value: &Value| pattern(src).bind(value, &families);
+        assert_eq!(bind("_", &Value::Int(1)), Some(vec![Value::Int(1)]));
+        assert_eq!(bind("1", &Value::Int(1)), Some(vec![Value::Int(1)]));
+        assert_eq!(bind("1", &Value::Int(2)), None);
+        assert_eq!(bind("Int", &Value::Flt(1.0)), None);
+        assert_eq!(bind("Circle", &circle), Some(vec![circle.clone()]));
+        assert_eq!(bind("Rect", &circle), None);
+    }
+
+    #[test]
+    fn bind_destructures_tuples_whose_fields_match() {
+        let families = Families::default();
+        let rect = tuple("Rect", vec![Value::Flt(2.0), Value::Flt(3.0)]);
+        let bind = |src| pattern(src).bind(&rect, &families);
+        let fields = Some(vec![Value::Flt(2.0), Value::Flt(3.0)]);
+        assert_eq!(bind("Rect _ _"), fields);
+        assert_eq!(bind("Rect Flt 3.0"), fields);
+        assert_eq!(bind("Rect _ 4.0"), None);
+        assert_eq!(bind("Rect Int _"), None);
+        // the number of fields must match
+        assert_eq!(bind("Rect _"), None);
+        assert_eq!(bind("Rect _ _ _"), None);
+        assert_eq!(pattern("Rect _ _").bind(&Value::Int(1), &families), None);
+    }
+
+    #[test]
+    fn check_patterns_rejects_malformed_patterns_before_match() {
+        let check = |src| check_patterns(&parse("<test>", src).unwrap());
+        assert!(check("[ [ Int ] [ ] [ x ] ] match").is_ok());
+        assert!(check("[ [ x ] [ ] ] match").is_err());
+        assert!(check("[ [ [ x ] [ ] ] match ]").is_err());
+        // the blocks are only patterns if they are written right before match
+        assert!(check("[ [ x ] [ ] ] cond").is_ok());
+    }
+
+    #[test]
+    fn family_patterns_match_their_variants() {
+        let families = Families::shapes();
+        let circle = tuple("Circle", vec![Value::Flt(2.0)]);
+        let bind = |src| pattern(src).bind(&circle, &families);
+        assert_eq!(bind("Shape"), Some(vec![circle.clone()]));
+        // a tuple pattern names the variant, not the family
+        assert_eq!(bind("Shape _"), None);
+    }
+
+    #[test]
+    fn finds_uncovered_variants() {
+        let families = Families::shapes();
+        let uncovered = |src| uncovered_variants(&parse("<test>", src).unwrap(), &families);
+        let shape = Symbol::new("Shape");
+        let rect = Symbol::new("Rect");
+        assert_eq!(uncovered("[ [ Circle ] [ ] ] match"), [(shape, vec![rect])]);
+        assert_eq!(
+            uncovered("[ [ Circle _ ] [ ] ] match"),
+            [(shape, vec![rect])]
+        );
+        assert_eq!(uncovered("[ [ Circle ] [ ] [ Rect ] [ ] ] match"), []);
+        assert_eq!(uncovered("[ [ Shape ] [ ] ] match"), []);
+        // a default case covers everything
+        assert_eq!(uncovered("[ [ Circle ] [ ] [ ] ] match"), []);
+        // a field pattern other than _ only covers some values of the variant
+        let circle = Symbol::new("Circle");
+        assert_eq!(
+            uncovered("[ [ Circle 1.0 ] [ ] [ Rect ] [ ] ] match"),
+            [(shape, vec![circle])]
+        );
+        // cases in nested blocks are checked too
+        assert_eq!(
+            uncovered("[ [ [ Rect ] [ ] ] match ]"),
+            [(shape, vec![circle])]
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use crate::interpreter::StackEffect;
+use crate::symbol::Symbol;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Formatter;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Number of executed ops between two samples of the call stack.
+const SAMPLE_INTERVAL: u32 = 64;
+
+/// Counts calls of composite words and the overloads selected for them, and samples the call
+/// stack to measure where time is spent. A sample is taken after the first op and then after every
+/// `SAMPLE_INTERVAL` ops, and attributes the time since the previous sample to the words on the
+/// call stack of the op that was just executed. If that op called a primitive, the primitive is
+/// the innermost word of the sample.
+pub struct Profiler {
+    words: HashMap<Symbol, WordStats>,
+    /// Time spent in each call stack, keyed by the words on the stack joined with `;`.
+    stacks: HashMap<String, Duration>,
+    last_sample: Instant,
+    /// The call stack of the last sample, which is charged for the time until the profiler stops.
+    last_stack: Vec<Symbol>,
+    countdown: u32,
+}
+
+#[derive(Default)]
+pub struct WordStats {
+    pub calls: u64,
+    /// Time spent in the word, including the words it called.
+    pub inclusive: Duration,
+    /// Time spent in the word itself.
+    pub exclusive: Duration,
+    /// How often each method of the word was selected.
+    pub overloads: Vec<(Arc<StackEffect>, u64)>,
+}
+
+/// Column by which the report is sorted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortBy {
+    Name,
+    Calls,
+    Inclusive,
+    Exclusive,
+}
+
+impl SortBy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "name" => Some(SortBy::Name),
+            "calls" => Some(SortBy::Calls),
+            "incl" | "inclusive" => Some(SortBy::Inclusive),
+            "excl" | "exclusive" => Some(SortBy::Exclusive),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            words: HashMap::new(),
+            stacks: HashMap::new(),
+            last_sample: Instant::now(),
+            last_stack: vec![],
+            countdown: 1,
+        }
+    }
+
+    pub fn stats(&self, word: Symbol) -> Option<&WordStats> {
+        self.words.get(&word)
+    }
+
+    pub(crate) fn record_call(&mut self, word: Symbol, effect: &Arc<StackEffect>) {
+        let stats = self.words.entry(word).or_default();
+        stats.calls += 1;
+        match stats
+            .overloads
+            .iter_mut()
+            .find(|(e, _)| Arc::ptr_eq(e, effect))
+        {
+            Some((_, count)) => *count += 1,
+            None => stats.overloads.push((effect.clone(), 1)),
+        }
+    }
+
+    /// Count an executed op. Returns true when the call stack should be sampled.
+    pub(crate) fn tick(&mut self) -> bool {
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = SAMPLE_INTERVAL;
+            return true;
+        }
+        false
+    }
+
+    /// Attribute the time since the last sample to the words on the call stack, outermost first.
+    pub(crate) fn sample(&mut self, stack: Vec<Symbol>) {
+        self.last_stack = stack;
+        self.flush();
+    }
+
+    /// Attribute the time since the last sample to the call stack of the last sample. Call this
+    /// when profiling stops, so that the ops after the last sample are accounted for.
+    pub fn flush(&mut self) {
+        let now = Instant::now();
+        let elapsed = now - self.last_sample;
+        self.last_sample = now;
+
+        let stack = &self.last_stack;
+        let key = match stack.is_empty() {
+            true => "<top level>".to_string(),
+            false => stack
+                .iter()
+                .map(Symbol::to_string)
+                .collect::<Vec<_>>()
+                .join(";"),
+        };
+        *self.stacks.entry(key).or_default() += elapsed;
+
+        if let Some(top) = stack.last() {
+            self.words.entry(*top).or_default().exclusive += elapsed;
+        }
+        let mut seen = HashSet::new();
+        for word in stack {
+            if seen.insert(word) {
+                self.words.entry(*word).or_default().inclusive += elapsed;
+            }
+        }
+    }
+
+    /// A table of the profiled words and their overloads.
+    pub fn report(&self, sort: SortBy) -> Report<'_> {
+        Report {
+            profiler: self,
+            sort,
+        }
+    }
+
+    /// The sampled call stacks in the folded format of flamegraph tools. The weight of each
+    /// stack is the time spent in it in microseconds.
+    pub fn folded(&self) -> String {
+        let mut stacks: Vec<_> = self.stacks.iter().collect();
+        stacks.sort();
+        let mut out = String::new();
+        for (stack, time) in stacks {
+            if time.as_micros() > 0 {
+                out += &format!("{stack} {}\n", time.as_micros());
+            }
+        }
+        out
+    }
+}
+
+pub struct Report<'a> {
+    profiler: &'a Profiler,
+    sort: SortBy,
+}
+
+impl std::fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut words: Vec<_> = self.profiler.words.iter().collect();
+        words.sort_by_key(|(word, _)| word.to_string());
+        match self.sort {
+            SortBy::Name => {}
+            SortBy::Calls => words.sort_by_key(|(_, stats)| Reverse(stats.calls)),
+            SortBy::Inclusive => words.sort_by_key(|(_, stats)| Reverse(stats.inclusive)),
+            SortBy::Exclusive => words.sort_by_key(|(_, stats)| Reverse(stats.exclusive)),
+        }
+
+        let ms = |time: Duration| time.as_secs_f64() * 1000.0;
+        writeln!(
+            f,
+            "{:<40} {:>10} {:>12} {:>12}",
+            "word", "calls", "incl ms", "excl ms"
+        )?;
+        for (word, stats) in words {
+            writeln!(
+                f,
+                "{:<40} {:>10} {:>12.3} {:>12.3}",
+                word.to_string(),
+                stats.calls,
+                ms(stats.inclusive),
+                ms(stats.exclusive)
+            )?;
+            let mut overloads: Vec<_> = stats.overloads.iter().collect();
+            overloads.sort_by_key(|(_, count)| Reverse(*count));
+            for (effect, count) in overloads {
+                writeln!(f, "    {:<36} {:>10}", effect.to_string(), count)?;
+            }
+        }
+        Ok(())
+    }
+}
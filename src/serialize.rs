@@ -8,17 +8,17 @@ impl Display for Value {
             Value::True => write!(f, "true"),
             Value::False => write!(f, "false"),
             Value::Int(x) => write!(f, "{x}"),
-            Value::Flt(x) => write!(f, "{x}"),
-            Value::Str(x) => write!(f, "{x:?}"),
+            Value::Flt(x) => write!(f, "{x:?}"),
+            Value::Str(x) => QuotedStr(x).fmt(f),
             Value::Symbol(s) => write!(f, "{s}"),
-            Value::Tuple(_) => unimplemented!(),
-            Value::Block(ops) => {
-                write!(f, "[")?;
-                for op in ops.iter() {
-                    write!(f, " {op}")?;
+            Value::Tuple(fields) => {
+                for field in &fields[1..] {
+                    write!(f, "{field} ")?;
                 }
-                write!(f, " ]")
+                fields[0].fmt(f)
             }
+            Value::Block(ops) => write_block(f, ops),
+            Value::Closure(closure) => write_block(f, &closure.ops()),
         }
     }
 }
@@ -27,13 +27,22 @@ impl Display for Op {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Op::Literal(value) => value.fmt(f),
-            Op::Symbol(name) => name.fmt(f),
-            Op::Tuple(_) => unimplemented!(),
-            Op::Select(i) => write!(f, "#{i}"),
-            Op::If => write!(f, "if"),
-            Op::BeginDef => write!(f, ":"),
-            Op::BeginTypeDef => write!(f, ":t"),
-            Op::End => write!(f, ";"),
+            Op::Symbol(name, _) => name.fmt(f),
+            // only occurs in the constructors of types, which are not serialized as code
+            Op::Tuple(n) => write!(f, "<tuple of {n}>"),
+            Op::Select(i, _) => write!(f, "#{i}"),
+            Op::Locals(names, _) => {
+                write!(f, "{{")?;
+                for name in names.iter() {
+                    write!(f, " {name}")?;
+                }
+                write!(f, " -- }}")
+            }
+            Op::If(_) => write!(f, "if"),
+            Op::Try(_) => write!(f, "try"),
+            Op::BeginDef(_) => write!(f, ":"),
+            Op::BeginTypeDef(_) => write!(f, ":t"),
+            Op::End(_) => write!(f, ";"),
             Op::Effect(effect) => effect.fmt(f),
         }
     }
@@ -57,6 +66,14 @@ impl Display for StackEffect {
     }
 }
 
+fn write_block(f: &mut Formatter<'_>, ops: &[Op]) -> std::fmt::Result {
+    write!(f, "[")?;
+    for op in ops {
+        write!(f, " {op}")?;
+    }
+    write!(f, " ]")
+}
+
 pub struct DisplayBlock<'a>(pub &'a [Op]);
 impl Display for DisplayBlock<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -70,3 +87,23 @@ impl Display for DisplayBlock<'_> {
         Ok(())
     }
 }
+
+/// Displays a string as a literal that reads back as the same string.
+pub struct QuotedStr<'a>(pub &'a str);
+impl Display for QuotedStr<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"")?;
+        for ch in self.0.chars() {
+            match ch {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '\t' => write!(f, "\\t")?,
+                '\r' => write!(f, "\\r")?,
+                _ if ch.is_control() => write!(f, "\\u{{{:x}}}", ch as u32)?,
+                _ => write!(f, "{ch}")?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
@@ -0,0 +1,92 @@
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+/// A named piece of source code, like a file or a line entered in the REPL. Spans share it, so
+/// it lives as long as some code, value or error refers to it.
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+impl Source {
+    pub fn new(name: &str, text: &str) -> Arc<Self> {
+        Arc::new(Source {
+            name: name.to_string(),
+            text: text.to_string(),
+        })
+    }
+}
+
+/// Leaves out the text, which is shown by the spans that point into it.
+impl std::fmt::Debug for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Source")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A range of bytes in a source. Spans don't implement `PartialEq`, because they are
+/// metadata about where code came from; values that hold them, like ops, compare without them.
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub source: Arc<Source>,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(source: Arc<Source>, start: usize, end: usize) -> Self {
+        Span { source, start, end }
+    }
+
+    /// Formats the span as `name:line:column`.
+    pub fn location(&self) -> String {
+        let (line, col) = line_col(&self.source.text, self.start);
+        format!("{}:{line}:{col}", self.source.name)
+    }
+}
+
+/// The 1-based line and column of a byte offset.
+fn line_col(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let col = before[line_start..].chars().count() + 1;
+    (line, col)
+}
+
+/// Shows the location of the span and underlines it in the source line, for example
+/// ```text
+///   --> session.forth:3:14
+///    |
+///  3 | : foo ( -- ) bar ;
+///    |              ^^^
+/// ```
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let source = &self.source;
+        let (line, col) = line_col(&source.text, self.start);
+
+        let line_start = source.text[..self.start]
+            .rfind('\n')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let line_end = source.text[self.start..]
+            .find('\n')
+            .map(|i| i + self.start)
+            .unwrap_or(source.text.len());
+        let text = &source.text[line_start..line_end];
+
+        let width = source.text[self.start..self.end.min(line_end)]
+            .chars()
+            .count()
+            .max(1);
+        let gutter = " ".repeat(line.to_string().len());
+
+        writeln!(f, "{gutter}--> {}", self.location())?;
+        writeln!(f, "{gutter} |")?;
+        writeln!(f, "{line} | {text}")?;
+        write!(f, "{gutter} | {}{}", " ".repeat(col - 1), "^".repeat(width))
+    }
+}
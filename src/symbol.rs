@@ -3,21 +3,27 @@ use std::collections::HashSet;
 use std::fmt::Formatter;
 use std::sync::RwLock;
 
-// predefined symbols
-/*pub const INT: Symbol = Symbol("Int");
-pub const FLT: Symbol = Symbol("Flt");
-pub const STR: Symbol = Symbol("Str");*/
+// Predefined symbols. They are statics rather than constants, so that every copy refers to the
+// same interned string.
+pub static BLN: Symbol = Symbol("Bln");
+pub static INT: Symbol = Symbol("Int");
+pub static FLT: Symbol = Symbol("Flt");
+pub static STR: Symbol = Symbol("Str");
+pub static SYM: Symbol = Symbol("Sym");
+pub static OPS: Symbol = Symbol("Ops");
 
 lazy_static! {
     static ref SYMBOLS: RwLock<HashSet<&'static str>> = {
-        let symbols = HashSet::new();
-        /*symbols.insert(INT.0);
-        symbols.insert(FLT.0);
-        symbols.insert(STR.0);*/
+        let mut symbols = HashSet::new();
+        for sym in [&BLN, &INT, &FLT, &STR, &SYM, &OPS] {
+            symbols.insert(sym.0);
+        }
         RwLock::new(symbols)
     };
 }
 
+// Hashing the contents is consistent with pointer equality because symbols are interned.
+#[allow(clippy::derived_hash_with_manual_eq)]
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd)]
 pub struct Symbol(pub &'static str);
 
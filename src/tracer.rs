@@ -0,0 +1,110 @@
+use crate::interpreter::{Op, StackEffect};
+use crate::symbol::Symbol;
+use crate::value::Value;
+use std::collections::HashSet;
+use std::io::Write;
+use std::sync::Arc;
+
+/// Number of values shown from the top of each stack.
+const STACK_PREVIEW: usize = 10;
+
+/// Writes a line for every executed op, indented by call depth and followed by the stack
+/// contents before the op. Calls show the signature of the selected method, and returns from
+/// words show the resulting stack. A word that tail-calls another word returns together with it.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    /// Only ops inside these words and calls to them are traced. If empty, everything is traced.
+    pub words: HashSet<Symbol>,
+}
+
+impl Tracer {
+    pub fn new(out: impl Write + 'static) -> Self {
+        Tracer {
+            out: Box::new(out),
+            words: HashSet::new(),
+        }
+    }
+
+    pub fn to_stderr() -> Self {
+        Tracer::new(std::io::stderr())
+    }
+
+    pub fn to_file(path: &str) -> std::io::Result<Self> {
+        let file = std::fs::File::create(path)?;
+        Ok(Tracer::new(std::io::BufWriter::new(file)))
+    }
+
+    /// Only trace the given words.
+    pub fn with_words(mut self, words: impl IntoIterator<Item = Symbol>) -> Self {
+        self.words.extend(words);
+        self
+    }
+
+    fn is_traced(&self, word: Option<Symbol>) -> bool {
+        self.words.is_empty() || word.is_some_and(|w| self.words.contains(&w))
+    }
+
+    pub(crate) fn op(
+        &mut self,
+        depth: usize,
+        word: Option<Symbol>,
+        op: &Op,
+        overload: Option<Arc<StackEffect>>,
+        stacks: (&[Value], &[Value]),
+    ) -> std::io::Result<()> {
+        let calls_traced_word = matches!(op, Op::Symbol(name, _) if self.words.contains(name));
+        if !self.is_traced(word) && !calls_traced_word {
+            return Ok(());
+        }
+        let text = match overload {
+            Some(effect) => format!("{op} {effect}"),
+            None => op.to_string(),
+        };
+        self.line(depth, &text, stacks)
+    }
+
+    pub(crate) fn ret(
+        &mut self,
+        depth: usize,
+        word: Symbol,
+        stacks: (&[Value], &[Value]),
+    ) -> std::io::Result<()> {
+        if !self.is_traced(Some(word)) {
+            return Ok(());
+        }
+        self.line(depth, &format!("end {word}"), stacks)
+    }
+
+    pub(crate) fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+
+    fn line(
+        &mut self,
+        depth: usize,
+        text: &str,
+        (main, secondary): (&[Value], &[Value]),
+    ) -> std::io::Result<()> {
+        let indent = "  ".repeat(depth);
+        write!(self.out, "{indent}{text:<30} |{}", StackPreview(main))?;
+        if !secondary.is_empty() {
+            write!(self.out, " ||{}", StackPreview(secondary))?;
+        }
+        writeln!(self.out)
+    }
+}
+
+struct StackPreview<'a>(&'a [Value]);
+
+impl std::fmt::Display for StackPreview<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let start = self.0.len().saturating_sub(STACK_PREVIEW);
+        if start > 0 {
+            write!(f, " ...")?;
+        }
+        for value in &self.0[start..] {
+            write!(f, " {value}")?;
+        }
+        Ok(())
+    }
+}
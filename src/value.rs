@@ -1,9 +1,10 @@
-use crate::errors::Result;
-use crate::interpreter::Op;
-use crate::symbol::Symbol;
+use crate::closure::{Closure, Quotation};
+use crate::compiler::Block;
+use crate::errors::{Error, ErrorKind, Result};
+use crate::symbol::{self, Symbol};
 use std::sync::Arc;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub enum Value {
     True,
     False,
@@ -12,21 +13,29 @@ pub enum Value {
     Str(Arc<String>),
     Symbol(Symbol),
     Tuple(Arc<Vec<Value>>),
-    Block(Arc<[Op]>),
+    Block(Arc<Block>),
+    /// A quotation built at runtime, which has the type `Ops` like a block.
+    Closure(Arc<Closure>),
 }
 
 impl Value {
+    fn type_mismatch(&self, expected: &'static str) -> Error {
+        Error::new(ErrorKind::TypeMismatch {
+            expected: Symbol::from_static(expected),
+            found: self.get_type(),
+        })
+    }
+
     pub fn get_type(&self) -> Symbol {
         match self {
-            Value::True => Symbol::from_static("Bln"),
-            Value::False => Symbol::from_static("Bln"),
-            Value::Int(_) => Symbol::from_static("Int"),
-            Value::Flt(_) => Symbol::from_static("Flt"),
-            Value::Str(_) => Symbol::from_static("Str"),
-            Value::Symbol(_) => Symbol::from_static("Sym"),
-            Value::Block(_) => Symbol::from_static("Ops"),
+            Value::True | Value::False => symbol::BLN,
+            Value::Int(_) => symbol::INT,
+            Value::Flt(_) => symbol::FLT,
+            Value::Str(_) => symbol::STR,
+            Value::Symbol(_) => symbol::SYM,
+            Value::Block(_) | Value::Closure(_) => symbol::OPS,
             Value::Tuple(fields) => match fields.as_slice() {
-                [Value::Symbol(tag), ..] => tag.clone(),
+                [Value::Symbol(tag), ..] => *tag,
                 _ => panic!("invalid tuple"),
             },
         }
@@ -36,70 +45,70 @@ impl Value {
         match self {
             Value::True => Ok(true),
             Value::False => Ok(false),
-            _ => Err(format!(
-                "Found a {} where Bln was expected",
-                self.get_type()
-            )),
+            _ => Err(self.type_mismatch("Bln")),
         }
     }
 
     pub fn expect_int(self) -> Result<i64> {
         match self {
             Value::Int(x) => Ok(x),
-            _ => Err(format!(
-                "Found a {} where Int was expected",
-                self.get_type()
-            )),
+            _ => Err(self.type_mismatch("Int")),
         }
     }
 
     pub fn expect_float(self) -> Result<f64> {
         match self {
             Value::Flt(x) => Ok(x),
-            _ => Err(format!(
-                "Found a {} where Flt was expected",
-                self.get_type()
-            )),
+            _ => Err(self.type_mismatch("Flt")),
         }
     }
 
     pub fn expect_string(self) -> Result<Arc<String>> {
         match self {
             Value::Str(x) => Ok(x),
-            _ => Err(format!(
-                "Found a {} where Str was expected",
-                self.get_type()
-            )),
+            _ => Err(self.type_mismatch("Str")),
         }
     }
 
     pub fn expect_symbol(self) -> Result<Symbol> {
         match self {
             Value::Symbol(s) => Ok(s),
-            _ => Err(format!(
-                "Found a {} where Sym was expected",
-                self.get_type()
-            )),
+            _ => Err(self.type_mismatch("Sym")),
         }
     }
 
     pub fn expect_tuple(self) -> Result<Arc<Vec<Value>>> {
         match self {
             Value::Tuple(x) => Ok(x),
-            _ => Err(format!(
-                "Found a {} where Tuple was expected",
-                self.get_type()
-            )),
+            _ => Err(self.type_mismatch("Tuple")),
         }
     }
 
-    pub fn expect_block(self) -> Result<Arc<[Op]>> {
+    pub fn expect_block(self) -> Result<Quotation> {
         match self {
-            Value::Block(ops) => Ok(ops),
-            _ => Err(format!(
-                "Found a {} where Ops was expected",
-                self.get_type()
-            )),
+            Value::Block(block) => Ok(Quotation::Block(block)),
+            Value::Closure(closure) => Ok(Quotation::Closure(closure)),
+            _ => Err(self.type_mismatch("Ops")),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::True, Value::True) | (Value::False, Value::False) => true,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Flt(a), Value::Flt(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            (Value::Tuple(a), Value::Tuple(b)) => a == b,
+            (Value::Block(a), Value::Block(b)) => a == b,
+            // closures are equal to the blocks they behave like
+            (Value::Block(_) | Value::Closure(_), Value::Block(_) | Value::Closure(_)) => {
+                let ops = |value: &Value| value.clone().expect_block().unwrap().ops();
+                ops(self) == ops(other)
+            }
+            _ => false,
         }
     }
 }